@@ -0,0 +1,423 @@
+//! Run an intcode program from the shell.
+//!
+//! ```text
+//! intcode <program> [--set ADDRESS=VALUE]... [--memory SIZE] [--input FILE] [--ascii]
+//!         [--coverage FILE] [--strict]
+//! intcode <program> --lint
+//! intcode <program> --gdb PORT [--set ADDRESS=VALUE]... [--memory SIZE] [--input FILE]
+//!         [--ascii] [--strict]
+//! ```
+//!
+//! The program is comma separated text or an intcode binary (see `intcode::binary`), whose
+//! memory size and patches are applied before `--memory` and `--set`.
+//!
+//! Input is read a line at a time from stdin (or `--input`) whenever the program asks for
+//! it. Each line is a single integer, or with `--ascii` the characters of the line followed
+//! by a newline. Outputs are printed as they are produced.
+//!
+//! With `--coverage` the addresses executed, read and written are merged into an lcov-like
+//! report at FILE, so running the same program with different inputs accumulates them.
+//! With `--strict` reading a cell that neither the program nor a `--set` initialized is a
+//! fault instead of a silent 0.
+//!
+//! A CPU fault is reported with a backtrace of the calls in progress (see
+//! `intcode::calls`).
+//!
+//! With `--lint` the program is checked statically instead of run, and the diagnostics
+//! are printed one per line.
+//!
+//! With `--gdb` the program waits for a debugger on 127.0.0.1:PORT and runs under its
+//! control (see `intcode::gdb`), starting with every value of `--input` queued.
+//!
+//! The exit code is 0 when the program halts, 1 when the CPU faults, 2 when the program
+//! wants input and none is left, 64 for bad command line arguments and 65 for input lines
+//! that cannot be read. Linting exits with 1 when there are errors.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process;
+
+use advent_of_code_2019::intcode::coverage::Coverage;
+use advent_of_code_2019::intcode::gdb::GdbStub;
+use advent_of_code_2019::intcode::lint::{self, Severity};
+use advent_of_code_2019::intcode::uninit::UninitMode;
+use advent_of_code_2019::intcode::{ExitReason, CPU};
+
+const EXIT_HALT: i32 = 0;
+const EXIT_FAULT: i32 = 1;
+const EXIT_INPUT_STARVED: i32 = 2;
+const EXIT_USAGE: i32 = 64;
+const EXIT_BAD_INPUT: i32 = 65;
+
+const USAGE: &str = "usage: intcode <program> [--set ADDRESS=VALUE]... [--memory SIZE] \
+                     [--input FILE] [--ascii] [--coverage FILE] [--strict]\n       \
+                     intcode <program> --lint\n       \
+                     intcode <program> --gdb PORT [--set ADDRESS=VALUE]... [--memory SIZE] \
+                     [--input FILE] [--ascii] [--strict]";
+
+#[derive(Debug, Default)]
+struct Options {
+    program: String,
+    patches: Vec<(usize, isize)>,
+    memory_size: Option<usize>,
+    input: Option<String>,
+    ascii: bool,
+    coverage: Option<String>,
+    strict: bool,
+    lint: bool,
+    gdb: Option<u16>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut program = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--set" => {
+                let patch = args.next().ok_or("--set needs ADDRESS=VALUE")?;
+                options.patches.push(parse_patch(&patch)?);
+            }
+            "--memory" => {
+                let size = args.next().ok_or("--memory needs a size")?;
+                options.memory_size = Some(
+                    size.parse()
+                        .map_err(|_| format!("Invalid memory size `{}`", size))?,
+                );
+            }
+            "--input" => {
+                options.input = Some(args.next().ok_or("--input needs a file")?);
+            }
+            "--ascii" => options.ascii = true,
+            "--strict" => options.strict = true,
+            "--lint" => options.lint = true,
+            "--coverage" => {
+                options.coverage = Some(args.next().ok_or("--coverage needs a file")?);
+            }
+            "--gdb" => {
+                let port = args.next().ok_or("--gdb needs a port")?;
+                options.gdb = Some(
+                    port.parse()
+                        .map_err(|_| format!("Invalid port `{}`", port))?,
+                );
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option `{}`", arg)),
+            _ => {
+                if program.replace(arg).is_some() {
+                    return Err("Only one program can be run".to_string());
+                }
+            }
+        }
+    }
+
+    options.program = program.ok_or_else(|| USAGE.to_string())?;
+    Ok(options)
+}
+
+fn parse_patch(patch: &str) -> Result<(usize, isize), String> {
+    let invalid = || format!("Invalid patch `{}`, expected ADDRESS=VALUE", patch);
+    let mut parts = patch.splitn(2, '=');
+    let address = parts.next().ok_or_else(invalid)?;
+    let value = parts.next().ok_or_else(invalid)?;
+    Ok((
+        address.trim().parse().map_err(|_| invalid())?,
+        value.trim().parse().map_err(|_| invalid())?,
+    ))
+}
+
+/// Turn the next line of input into the values handed to the program
+fn read_values(reader: &mut dyn BufRead, ascii: bool) -> Result<Option<Vec<isize>>, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(error) => return Err(format!("Error reading input : {}", error)),
+    }
+
+    let line = line.trim_end_matches(['\n', '\r']);
+    if ascii {
+        Ok(Some(
+            line.chars()
+                .map(|c| c as isize)
+                .chain(std::iter::once('\n' as isize))
+                .collect(),
+        ))
+    } else {
+        match line.trim().parse::<isize>() {
+            Ok(value) => Ok(Some(vec![value])),
+            Err(_) => Err(format!("Invalid input `{}`, expected an integer", line)),
+        }
+    }
+}
+
+fn print_output(value: isize, ascii: bool) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if ascii && (0..128).contains(&value) {
+        let _ = write!(stdout, "{}", value as u8 as char);
+    } else {
+        let _ = writeln!(stdout, "{}", value);
+    }
+    let _ = stdout.flush();
+}
+
+/// Merge the coverage collected by `cpu` into the report at `path`
+fn save_coverage(cpu: &mut CPU, options: &Options, path: &str) {
+    let mut coverage = match fs::read_to_string(path) {
+        Ok(report) => match Coverage::from_lcov(&report) {
+            Ok(coverage) => coverage,
+            Err(error) => {
+                eprintln!("{} : {}", path, error);
+                return;
+            }
+        },
+        Err(_) => Coverage::new(),
+    };
+    if let Some(collected) = cpu.take_coverage() {
+        coverage.merge(&collected);
+    }
+    if let Err(error) = fs::write(path, coverage.to_lcov(&options.program)) {
+        eprintln!("{} : {}", path, error);
+    }
+}
+
+fn run(options: &Options) -> i32 {
+    if options.lint {
+        return check(options);
+    }
+    if let Some(port) = options.gdb {
+        return debug(options, port);
+    }
+    let mut cpu = match load(options) {
+        Ok(cpu) => cpu,
+        Err(code) => return code,
+    };
+    let code = execute(&mut cpu, options);
+    if let Some(path) = &options.coverage {
+        save_coverage(&mut cpu, options, path);
+    }
+    code
+}
+
+fn check(options: &Options) -> i32 {
+    let cpu = match CPU::from_file(&options.program) {
+        Ok(cpu) => cpu,
+        Err(error) => {
+            eprintln!("{} : {}", options.program, error);
+            return EXIT_USAGE;
+        }
+    };
+    let diagnostics = lint::lint(&cpu.memory().to_vec());
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic.render(&options.program));
+    }
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.lint.severity() == Severity::Error)
+    {
+        EXIT_FAULT
+    } else {
+        EXIT_HALT
+    }
+}
+
+/// Serve the program to one debugger
+fn debug(options: &Options, port: u16) -> i32 {
+    let cpu = match load(options) {
+        Ok(cpu) => cpu,
+        Err(code) => return code,
+    };
+    let mut stub = GdbStub::new(cpu);
+    if let Some(path) = &options.input {
+        let mut reader = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(error) => {
+                eprintln!("{} : {}", path, error);
+                return EXIT_USAGE;
+            }
+        };
+        loop {
+            match read_values(&mut reader, options.ascii) {
+                Ok(Some(values)) => stub.push_input(&values),
+                Ok(None) => break,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    return EXIT_BAD_INPUT;
+                }
+            }
+        }
+    }
+
+    let session = TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+        eprintln!("Waiting for gdb on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stub.serve(stream)
+    });
+    match session {
+        Ok(()) => EXIT_HALT,
+        Err(error) => {
+            eprintln!("gdb : {}", error);
+            EXIT_FAULT
+        }
+    }
+}
+
+fn load(options: &Options) -> Result<CPU, i32> {
+    let mut cpu = match CPU::from_file(&options.program) {
+        Ok(cpu) => cpu,
+        Err(error) => {
+            eprintln!("{} : {}", options.program, error);
+            return Err(EXIT_USAGE);
+        }
+    };
+    if let Some(size) = options.memory_size {
+        cpu.set_memory_size(size);
+    }
+    if options.strict {
+        cpu.set_uninit_detection(UninitMode::Error);
+    }
+    let size = cpu.memory().len();
+    for (address, value) in &options.patches {
+        if *address >= size {
+            eprintln!(
+                "--set {}={} : address is out of bounds for memory of size {}",
+                address, value, size
+            );
+            return Err(EXIT_USAGE);
+        }
+        cpu.set_memory(*address, *value);
+    }
+    cpu.set_exit_on_output();
+    cpu.set_call_tracking();
+    if options.coverage.is_some() {
+        cpu.enable_coverage();
+    }
+    Ok(cpu)
+}
+
+fn execute(cpu: &mut CPU, options: &Options) -> i32 {
+    let mut reader: Box<dyn BufRead> = match &options.input {
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(error) => {
+                eprintln!("{} : {}", path, error);
+                return EXIT_USAGE;
+            }
+        },
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let mut pending: VecDeque<isize> = VecDeque::new();
+    let mut next_input: Option<String> = None;
+    loop {
+        match cpu.run(next_input.as_deref()) {
+            Ok(ExitReason::Halt) => return EXIT_HALT,
+            Ok(ExitReason::OutputGenerated) => {
+                if let Some(value) = cpu.get_last_output() {
+                    print_output(*value, options.ascii);
+                }
+                next_input = None;
+            }
+            Ok(ExitReason::InputRequired) => {
+                if pending.is_empty() {
+                    match read_values(&mut reader, options.ascii) {
+                        Ok(Some(values)) => pending.extend(values),
+                        Ok(None) => {
+                            eprintln!("Program requires input but none is left");
+                            return EXIT_INPUT_STARVED;
+                        }
+                        Err(error) => {
+                            eprintln!("{}", error);
+                            return EXIT_BAD_INPUT;
+                        }
+                    }
+                }
+                // One value per run, so nothing is lost if the program outputs before
+                // asking for the next one
+                next_input = pending.pop_front().map(|value| value.to_string());
+            }
+            Err(error) => {
+                eprintln!("CPU fault : {:?}", error);
+                if let Some(backtrace) = cpu.backtrace() {
+                    eprintln!("{}", backtrace);
+                }
+                return EXIT_FAULT;
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    process::exit(run(&options));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(args(
+            "day2.txt --set 1=12 --set 2=2 --memory 4096 --ascii --coverage day2.lcov --strict",
+        ))
+        .expect("Valid arguments");
+        assert_eq!(options.program, "day2.txt");
+        assert_eq!(options.patches, vec![(1, 12), (2, 2)]);
+        assert_eq!(options.memory_size, Some(4096));
+        assert!(options.ascii);
+        assert_eq!(options.coverage.as_deref(), Some("day2.lcov"));
+        assert!(options.strict);
+        assert_eq!(options.gdb, None);
+
+        let options = parse_args(args("day9.txt --gdb 1234")).expect("Valid arguments");
+        assert_eq!(options.gdb, Some(1234));
+    }
+
+    #[test]
+    fn test_bad_args() {
+        assert!(parse_args(args("")).is_err());
+        assert!(parse_args(args("a.txt b.txt")).is_err());
+        assert!(parse_args(args("a.txt --set 1")).is_err());
+        assert!(parse_args(args("a.txt --set x=1")).is_err());
+        assert!(parse_args(args("a.txt --bogus")).is_err());
+        assert!(parse_args(args("a.txt --gdb 70000")).is_err());
+    }
+
+    #[test]
+    fn test_set_out_of_bounds() {
+        let path = std::env::temp_dir().join(format!("intcode-set-{}.txt", std::process::id()));
+        std::fs::write(&path, "1,0,0,0,99").expect("Writable temp dir");
+        let line = format!("{} --set 4=1 --memory 8 --set 7=1", path.display());
+        assert!(load(&parse_args(args(&line)).expect("Valid arguments")).is_ok());
+        let line = format!("{} --memory 8 --set 8=1", path.display());
+        let result = load(&parse_args(args(&line)).expect("Valid arguments"));
+        std::fs::remove_file(&path).ok();
+        assert_eq!(result.err(), Some(EXIT_USAGE));
+    }
+
+    #[test]
+    fn test_read_values() {
+        let mut reader = "12\nnorth\n".as_bytes();
+        assert_eq!(read_values(&mut reader, false), Ok(Some(vec![12])));
+        assert_eq!(
+            read_values(&mut reader, true),
+            Ok(Some(vec![110, 111, 114, 116, 104, 10]))
+        );
+        assert_eq!(read_values(&mut reader, true), Ok(None));
+    }
+}
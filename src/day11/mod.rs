@@ -1,4 +1,5 @@
 use crate::intcode::*;
+use crate::trace::{self, Event, SharedTracer, Verbosity};
 
 use itertools::Itertools;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
@@ -9,6 +10,8 @@ use std::convert::Into;
 use std::convert::TryFrom;
 use std::fmt;
 
+const LOOP_CHECK_INTERVAL: usize = 1000;
+
 #[derive(Debug, Clone, Copy, TryFromPrimitive)]
 #[repr(isize)]
 enum Color {
@@ -77,6 +80,7 @@ struct Robot {
     direction: Direction,
     location: Coordinates,
     camera: HashMap<(isize, isize), Color>,
+    tracer: SharedTracer,
 }
 
 impl Robot {
@@ -86,9 +90,11 @@ impl Robot {
             direction: Direction::Up,
             location: Coordinates::new(),
             camera: HashMap::new(),
+            tracer: trace::global(),
         };
         robot.brain.set_exit_on_output();
         robot.brain.set_memory_size(memory_size);
+        robot.brain.set_loop_detection(LOOP_CHECK_INTERVAL);
         robot
     }
 
@@ -103,7 +109,12 @@ impl Robot {
                     let color = self.read_camera();
                     input_string = color.to_string();
                     input = Some(&input_string);
-                    debug_print!("Tile is : {:?}\nInput is : {:?}", color, input);
+                    let (x, y) = self.location.into();
+                    self.tracer.emit(Verbosity::Debug, || Event::PanelRead {
+                        x,
+                        y,
+                        color: format!("{:?}", color),
+                    });
                     continue;
                 }
                 Ok(ExitReason::OutputGenerated) => match output_type {
@@ -111,9 +122,13 @@ impl Robot {
                         let color = Color::try_from(
                             *self.brain.get_last_output().expect("No output in CPU"),
                         );
-                        debug_print!("We need to paint : {:?}", color);
-
                         if let Ok(color) = color {
+                            let (x, y) = self.location.into();
+                            self.tracer.emit(Verbosity::Debug, || Event::PanelPainted {
+                                x,
+                                y,
+                                color: format!("{:?}", color),
+                            });
                             self.camera.insert(self.location.into(), color);
                         } else {
                             return Err(CpuError::InvalidOutputGenerated);
@@ -127,6 +142,12 @@ impl Robot {
 
                         if let Ok(direction) = direction {
                             self.move_robot(direction);
+                            let (x, y) = self.location.into();
+                            self.tracer.emit(Verbosity::Debug, || Event::RobotMoved {
+                                x,
+                                y,
+                                direction: format!("{:?}", self.direction),
+                            });
                         } else {
                             return Err(CpuError::InvalidOutputGenerated);
                         }
@@ -134,16 +155,13 @@ impl Robot {
                         output_type = OutputType::Paint;
                     }
                 },
-                _ => {
-                    println!("Error");
-                }
+                Err(error) => return Err(error),
             }
             input = None;
         }
     }
 
     fn read_camera(&mut self) -> Color {
-        debug_print!("Reading Paint");
         *self
             .camera
             .entry(self.location.into())
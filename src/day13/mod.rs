@@ -1,4 +1,5 @@
 use crate::intcode::*;
+use crate::trace::{self, Event, SharedTracer, Verbosity};
 
 use itertools::Itertools;
 use rayon::prelude::*;
@@ -10,6 +11,7 @@ use std::convert::TryFrom;
 use std::convert::Into;
 
 const SCREEN_SIZE : usize = 40;
+const LOOP_CHECK_INTERVAL: usize = 1000;
 
 #[derive(Debug, Copy, Clone)]
 enum OutputType {
@@ -32,6 +34,7 @@ struct Arcade {
     screen: HashMap<usize, Vec<Tile>>,
     joystick: Direction,
     score: isize,
+    tracer: SharedTracer,
 }
 
 impl Arcade {
@@ -41,9 +44,11 @@ impl Arcade {
             screen: HashMap::new(),
             joystick: Direction::Neutral,
             score: 0,
+            tracer: trace::global(),
         };
         arcade.brain.set_exit_on_output();
         arcade.brain.set_memory_size(memory_size);
+        arcade.brain.set_loop_detection(LOOP_CHECK_INTERVAL);
         arcade
     }
 
@@ -79,6 +84,8 @@ impl Arcade {
                         OutputType::TileID => {
                             if x_pos == -1 && y_pos == 0 {
                                 self.score = *self.brain.get_last_output().expect("No output for TileID");
+                                let score = self.score;
+                                self.tracer.emit(Verbosity::Debug, || Event::ScoreChanged { score });
                             } else {
                                 tile_id = Tile::try_from(*self.brain.get_last_output().expect("No output for TileID")).expect("Invalid Tile ID");
                                 self.tile_entry(x_pos as usize, y_pos as usize, tile_id);
@@ -87,15 +94,18 @@ impl Arcade {
                         },
                     }
                 },
-                _ => {
-                    println!("Error");
-                }
+                Err(error) => return Err(error),
             }
             input = None;
         }
     }
 
     fn tile_entry(&mut self, x: usize, y: usize, tile_id: Tile) {
+        self.tracer.emit(Verbosity::Debug, || Event::TileDrawn {
+            x,
+            y,
+            tile: format!("{:?}", tile_id),
+        });
 
         self.screen.entry(y).or_insert(vec![Tile::Empty; SCREEN_SIZE])[x] = tile_id;
 
@@ -1,6 +1,8 @@
 use std::collections::{HashMap, HashSet, hash_map::Entry};
 use rayon::prelude::*;
 
+use crate::trace::{self, Event, SharedTracer, Verbosity};
+
 #[derive(Debug, Clone)]
 struct Planet<'a> {
     id: usize,
@@ -57,13 +59,15 @@ impl Orbit {
 struct Universe<'a> {
     planets: HashMap<&'a str, Planet<'a>>,
     lanes : HashMap<usize, usize>,
+    tracer: SharedTracer,
 }
 
 impl<'a> Universe<'a> {
     pub fn new(input: &'a Vec<Orbit>) -> Universe<'a> {
         let mut universe = Universe {
             planets: HashMap::new(),
-            lanes: HashMap::new()
+            lanes: HashMap::new(),
+            tracer: trace::global(),
         };
 
         let mut planet_id = 0;
@@ -154,12 +158,10 @@ impl<'a> Universe<'a> {
         });
 
         let planet = self.planets.get(planet_name.as_str()).unwrap();
-        debug_print!(
-            "Planet : {} - {:?} - {:?}",
-            planet.id,
-            planet.orbits,
-            planet.orbiting_planets
-        );
+        self.tracer.emit(Verbosity::Debug, || Event::PlanetVisited {
+            id: planet.id,
+            name: planet_name.clone(),
+        });
 
         let mut possible_visit_locations: HashSet<String> = HashSet::new();
         if let Some(val) = planet.orbits {
@@ -314,4 +316,28 @@ mod tests {
 
         assert_eq!(d6p2(&orbits), 4);
     }
+
+    #[test]
+    fn test_trace_path() {
+        use crate::trace::MemoryTracer;
+        use std::sync::Arc;
+
+        let orbits = process_input("COM)B\nB)C\nB)YOU\nC)SAN");
+        let tracer = Arc::new(MemoryTracer::new(Verbosity::Debug));
+        let mut universe = Universe::new(&orbits);
+        universe.tracer = tracer.clone();
+
+        universe.find_path("YOU", "SAN");
+        let mut visited: Vec<_> = tracer
+            .events()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::PlanetVisited { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect();
+        // Neighbours are explored in parallel, so only the set of planets is stable
+        visited.sort();
+        assert_eq!(visited, vec!["B", "C", "COM"]);
+    }
 }
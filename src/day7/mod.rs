@@ -1,6 +1,9 @@
 use itertools::Itertools;
 use rayon::prelude::*;
 use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::fmt;
+
+use crate::trace::{self, Event, SharedTracer, Verbosity};
 
 macro_rules! address_or_value {
     ($iter_name:ident, $cpu:ident, $expression:expr) => {
@@ -23,12 +26,13 @@ enum CpuError {
     OutputGenerated,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct CPU {
     memory: Vec<isize>,
     instruction_pointer: usize,
     last_instruction: Option<Instruction>,
     output: Vec<usize>,
+    tracer: SharedTracer,
 }
 
 impl CPU {
@@ -42,61 +46,67 @@ impl CPU {
             instruction_pointer: 0,
             last_instruction: None,
             output: vec![],
+            tracer: trace::global(),
         }
     }
 
     pub fn run(&mut self, input: Option<&str>) -> CpuResult<()> {
         let mut user_input = input.unwrap_or("").trim().lines();
         loop {
-            match self.parse()? {
+            let ip = self.instruction_pointer;
+            let instruction = self.parse()?;
+            self.tracer
+                .emit(Verbosity::Trace, || Event::InstructionExecuted {
+                    ip,
+                    text: instruction.to_string(),
+                });
+            match instruction {
                 Instruction::Add(left, right, location) => {
-                    debug_print!("Add : {} + {} @ {}", left, right, location);
                     self.last_instruction = Some(Instruction::Add(left, right, location));
                     self.set_memory(location as usize, left + right);
                 }
                 Instruction::Mult(left, right, location) => {
-                    debug_print!("Mult : {} * {} @ {}", left, right, location);
                     self.last_instruction = Some(Instruction::Mult(left, right, location));
                     self.set_memory(location as usize, left * right);
                 }
                 Instruction::In(location) => {
-                    debug_print!("In : @ {}", location);
                     self.last_instruction = Some(Instruction::In(location));
-                    self.set_memory(
-                        location as usize,
-                        if input.is_some() {
-                            match user_input.next() {
-                                Some(val) => val.trim().parse::<isize>().unwrap(),
-                                None => return Err(CpuError::InputRequired),
-                            }
-                        } else {
+                    let value = match user_input.next() {
+                        Some(val) => val.trim().parse::<isize>().unwrap(),
+                        None => {
+                            self.tracer
+                                .emit(Verbosity::Debug, || Event::InputRequired { ip });
                             return Err(CpuError::InputRequired);
-                        },
-                    );
+                        }
+                    };
+                    self.tracer.emit(Verbosity::Debug, || Event::InputConsumed {
+                        ip,
+                        address: location as usize,
+                        value,
+                    });
+                    self.set_memory(location as usize, value);
                 }
                 Instruction::Out(value) => {
-                    debug_print!("Out : {}", value);
+                    self.tracer
+                        .emit(Verbosity::Debug, || Event::OutputProduced { ip, value });
                     self.last_instruction = Some(Instruction::Out(value));
                     self.output.push(value as usize);
                     self.increment_ip()?;
                     return Err(CpuError::OutputGenerated);
                 }
                 Instruction::JumpIfTrue(value, new_ip) => {
-                    debug_print!("JIT : {} to {}", value, new_ip);
                     self.last_instruction = Some(Instruction::JumpIfTrue(value, new_ip));
                     if value != 0 {
                         self.instruction_pointer = new_ip as usize;
                     }
                 }
                 Instruction::JumpIfFalse(value, new_ip) => {
-                    debug_print!("JIF : {} to {}", value, new_ip);
                     self.last_instruction = Some(Instruction::JumpIfFalse(value, new_ip));
                     if value == 0 {
                         self.instruction_pointer = new_ip as usize;
                     }
                 }
                 Instruction::LessThan(left, right, location) => {
-                    debug_print!("LT : {} < {} @ {}", left, right, location);
                     self.last_instruction = Some(Instruction::LessThan(left, right, location));
                     if left < right {
                         self.set_memory(location as usize, 1);
@@ -105,7 +115,6 @@ impl CPU {
                     }
                 }
                 Instruction::Equal(left, right, location) => {
-                    debug_print!("EQ : {} < {} @ {}", left, right, location);
                     self.last_instruction = Some(Instruction::Equal(left, right, location));
                     if left == right {
                         self.set_memory(location as usize, 1);
@@ -114,7 +123,7 @@ impl CPU {
                     }
                 }
                 Instruction::Halt => {
-                    debug_print!("Halt");
+                    self.tracer.emit(Verbosity::Info, || Event::Halted { ip });
                     self.last_instruction = Some(Instruction::Halt);
                     return Ok(());
                 }
@@ -251,6 +260,30 @@ impl Instruction {
     }
 }
 
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Add(left, right, location) => {
+                write!(f, "Add : {} + {} @ {}", left, right, location)
+            }
+            Instruction::Mult(left, right, location) => {
+                write!(f, "Mult : {} * {} @ {}", left, right, location)
+            }
+            Instruction::In(location) => write!(f, "In : @ {}", location),
+            Instruction::Out(value) => write!(f, "Out : {}", value),
+            Instruction::JumpIfTrue(value, new_ip) => write!(f, "JIT : {} to {}", value, new_ip),
+            Instruction::JumpIfFalse(value, new_ip) => write!(f, "JIF : {} to {}", value, new_ip),
+            Instruction::LessThan(left, right, location) => {
+                write!(f, "LT : {} < {} @ {}", left, right, location)
+            }
+            Instruction::Equal(left, right, location) => {
+                write!(f, "EQ : {} == {} @ {}", left, right, location)
+            }
+            Instruction::Halt => write!(f, "Halt"),
+        }
+    }
+}
+
 fn get_input() -> Result<isize, &'static str> {
     use std::io;
 
@@ -270,49 +303,20 @@ fn get_input() -> Result<isize, &'static str> {
 
 #[aoc(day7, part1)]
 fn d7p1(input: &str) -> usize {
-    let orig_cpu = CPU::new(input);
-
-    let mut output = 0;
-    let mut max = 0;
-    (0..5).permutations(5).for_each(|permutation| {
-        debug_print!("Permutation : {:?}", permutation);
-        permutation.iter().for_each(|entry| {
-            let mut amp = orig_cpu.clone();
-            let mut formatting = format!("{}\n{}", entry, output);
-            let mut vm_input = Some(formatting.as_str());
-            loop {
-                match amp.run(vm_input) {
-                    CpuResult::Ok(_) => {
-                        output = *amp.output.last().expect("No output provided by VM");
-                        debug_print!("Completed : {}", output);
-                        break;
-                    }
-                    CpuResult::Err(err) => match err {
-                        CpuError::InputRequired => {
-                            let val = get_input().expect("Error while getting user input");
-                            formatting = format!("{}", val);
-                            vm_input = Some(formatting.as_str());
-                        }
-                        CpuError::OutputGenerated => {}
-                        _ => {
-                            panic!(
-                                "Error while running through : {:?} - {:?}",
-                                permutation, err
-                            );
-                        }
-                    },
+    use crate::intcode::{search::Search, ExitReason};
+
+    Search::new(&crate::intcode::CPU::new(input))
+        .input_permutations(&[0, 1, 2, 3, 4])
+        .max_by_score(|program, candidate| {
+            candidate.input.iter().try_fold(0, |signal, phase| {
+                let mut amp = program.clone();
+                if amp.run(Some(&format!("{}\n{}", phase, signal))) != Ok(ExitReason::Halt) {
+                    return None;
                 }
-            }
-        });
-
-        if output > max {
-            max = output;
-        }
-
-        output = 0;
-    });
-
-    max
+                amp.get_last_output().copied()
+            })
+        })
+        .map_or(0, |(_, thrust)| thrust as usize)
 }
 
 #[aoc(day7, part2)]
@@ -394,6 +398,87 @@ fn d7p2(input: &str) -> usize {
     max
 }
 
+#[aoc(day7, part2, async)]
+fn d7p2_async(input: &str) -> usize {
+    use crate::intcode::asynchronous::{AsyncCpu, Executor};
+    use crate::intcode::search::Search;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    Search::new(&crate::intcode::CPU::new(input))
+        .input_permutations(&[5, 6, 7, 8, 9])
+        .max_by_score(|program, candidate| {
+            let amps: Vec<AsyncCpu> = candidate
+                .input
+                .iter()
+                .map(|phase| {
+                    let amp = AsyncCpu::new(program.clone());
+                    amp.send(*phase);
+                    amp
+                })
+                .collect();
+            amps[0].send(0);
+
+            // Every amp feeds the next one, and the last one loops back to the first
+            let thrust = Rc::new(Cell::new(0));
+            let mut executor = Executor::new();
+            let senders: Vec<_> = amps.iter().map(AsyncCpu::sender).collect();
+            for (index, mut amp) in amps.into_iter().enumerate() {
+                let next = senders[(index + 1) % senders.len()].clone();
+                let last = index == senders.len() - 1;
+                let thrust = thrust.clone();
+                executor.spawn(async move {
+                    while let Some(value) = amp.next_output().await.expect("Amplifier fault") {
+                        if last {
+                            thrust.set(value);
+                        }
+                        next.send(value);
+                    }
+                });
+            }
+            drop(senders);
+            executor.run();
+            Some(thrust.get())
+        })
+        .map_or(0, |(_, thrust)| thrust as usize)
+}
+
+#[aoc(day7, part2, threads)]
+fn d7p2_threads(input: &str) -> usize {
+    use crate::intcode::{search::Search, threaded::ThreadedRunner, ExitReason};
+    use std::sync::mpsc::channel;
+
+    Search::new(&crate::intcode::CPU::new(input))
+        .input_permutations(&[5, 6, 7, 8, 9])
+        .max_by_score(|program, candidate| {
+            // Channel i feeds amp i, and the last amp feeds the first
+            let (senders, receivers): (Vec<_>, Vec<_>) = candidate
+                .input
+                .iter()
+                .map(|phase| {
+                    let (sender, receiver) = channel();
+                    sender.send(*phase).expect("Receiver is still alive");
+                    (sender, receiver)
+                })
+                .unzip();
+            senders[0].send(0).expect("Receiver is still alive");
+
+            let mut runner = ThreadedRunner::new();
+            for (index, receiver) in receivers.into_iter().enumerate() {
+                let next = senders[(index + 1) % senders.len()].clone();
+                runner.add(program.clone(), receiver, next);
+            }
+            drop(senders);
+            let finished = runner.run();
+            let last = finished.last().expect("Five amplifiers").as_ref().ok()?;
+            if last.exit != Ok(ExitReason::Halt) {
+                return None;
+            }
+            last.cpu.get_last_output().copied()
+        })
+        .map_or(0, |(_, thrust)| thrust as usize)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -427,4 +512,60 @@ mod tests {
     fn test5() {
         assert_eq!(d7p2(&"3,52,1001,52,-5,52,3,53,1,52,56,54,1007,54,5,55,1005,55,26,1001,54,-5,54,1105,1,12,1,53,54,53,1008,54,0,55,1001,55,1,55,2,53,55,53,4,53,1001,56,-1,56,1005,56,6,99,0,0,0,0,10"), 18216);
     }
+
+    #[test]
+    fn test_trace_events() {
+        use crate::trace::MemoryTracer;
+        use std::sync::Arc;
+
+        let tracer = Arc::new(MemoryTracer::new(Verbosity::Trace));
+        let mut cpu = CPU::new("3,9,1001,9,1,9,4,9,99,0");
+        cpu.tracer = tracer.clone();
+
+        assert!(matches!(
+            cpu.run(Some("41")),
+            Err(CpuError::OutputGenerated)
+        ));
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(
+            tracer.events(),
+            vec![
+                Event::InstructionExecuted {
+                    ip: 0,
+                    text: "In : @ 9".to_string()
+                },
+                Event::InputConsumed {
+                    ip: 0,
+                    address: 9,
+                    value: 41
+                },
+                Event::InstructionExecuted {
+                    ip: 2,
+                    text: "Add : 41 + 1 @ 9".to_string()
+                },
+                Event::InstructionExecuted {
+                    ip: 6,
+                    text: "Out : 42".to_string()
+                },
+                Event::OutputProduced { ip: 6, value: 42 },
+                Event::InstructionExecuted {
+                    ip: 8,
+                    text: "Halt".to_string()
+                },
+                Event::Halted { ip: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_async() {
+        assert_eq!(d7p2_async("3,26,1001,26,-4,26,3,27,1002,27,2,27,1,27,26,27,4,27,1001,28,-1,28,1005,28,6,99,0,0,5"), 139629729);
+        assert_eq!(d7p2_async("3,52,1001,52,-5,52,3,53,1,52,56,54,1007,54,5,55,1005,55,26,1001,54,-5,54,1105,1,12,1,53,54,53,1008,54,0,55,1001,55,1,55,2,53,55,53,4,53,1001,56,-1,56,1005,56,6,99,0,0,0,0,10"), 18216);
+    }
+
+    #[test]
+    fn test_threads() {
+        assert_eq!(d7p2_threads("3,26,1001,26,-4,26,3,27,1002,27,2,27,1,27,26,27,4,27,1001,28,-1,28,1005,28,6,99,0,0,5"), 139629729);
+        assert_eq!(d7p2_threads("3,52,1001,52,-5,52,3,53,1,52,56,54,1007,54,5,55,1005,55,26,1001,54,-5,54,1105,1,12,1,53,54,53,1008,54,0,55,1001,55,1,55,2,53,55,53,4,53,1001,56,-1,56,1005,56,6,99,0,0,0,0,10"), 18216);
+    }
 }
@@ -0,0 +1,311 @@
+//! Async driver for the intcode CPU. An `AsyncCpu` owns an input queue that anything can
+//! feed through `send` or a `Sender`, and awaiting `next_output` runs the VM until it
+//! outputs, waiting on the queue whenever the program asks for input. Nothing here depends
+//! on a particular executor; `Executor` is a small single threaded one that is enough to
+//! run a handful of CPUs and their peripherals as tasks.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+use super::{CpuResult, ExitReason, CPU};
+
+#[derive(Debug, Default)]
+struct Queue {
+    values: VecDeque<isize>,
+    waker: Option<Waker>,
+    senders: usize,
+}
+
+/// Feeds values to the `AsyncCpu` it was taken from. The input is closed once every sender
+/// is dropped.
+#[derive(Debug)]
+pub struct Sender {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Sender {
+    pub fn send(&self, value: isize) {
+        push(&self.queue, value);
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Sender {
+        self.queue.lock().unwrap().senders += 1;
+        Sender {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+fn push(queue: &Mutex<Queue>, value: isize) {
+    let mut queue = queue.lock().unwrap();
+    queue.values.push_back(value);
+    if let Some(waker) = queue.waker.take() {
+        waker.wake();
+    }
+}
+
+/// Resolves to the next queued value, or None once the queue is empty and has no senders
+struct Recv<'a> {
+    queue: &'a Mutex<Queue>,
+}
+
+impl Future for Recv<'_> {
+    type Output = Option<isize>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<isize>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(value) = queue.values.pop_front() {
+            Poll::Ready(Some(value))
+        } else if queue.senders == 0 {
+            Poll::Ready(None)
+        } else {
+            queue.waker = Some(context.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AsyncCpu {
+    cpu: CPU,
+    queue: Arc<Mutex<Queue>>,
+    exit_reason: Option<ExitReason>,
+}
+
+impl AsyncCpu {
+    pub fn new(cpu: CPU) -> AsyncCpu {
+        let mut cpu = cpu;
+        cpu.set_exit_on_output();
+        AsyncCpu {
+            cpu,
+            queue: Arc::new(Mutex::new(Queue::default())),
+            exit_reason: None,
+        }
+    }
+
+    /// Queue a value for the program to read
+    pub fn send(&self, value: isize) {
+        push(&self.queue, value);
+    }
+
+    /// A handle other tasks can feed this CPU's input through
+    pub fn sender(&self) -> Sender {
+        self.queue.lock().unwrap().senders += 1;
+        Sender {
+            queue: self.queue.clone(),
+        }
+    }
+
+    /// Run until the program outputs a value. None means it stopped for good instead:
+    /// it halted, or it wants input and every `Sender` is gone, which `exit_reason` tells
+    /// apart.
+    pub async fn next_output(&mut self) -> CpuResult<Option<isize>> {
+        let mut input: Option<String> = None;
+        loop {
+            // One value per run, so nothing queued is lost when the program outputs first
+            let reason = self.cpu.run(input.take().as_deref())?;
+            self.exit_reason = Some(reason);
+            match reason {
+                ExitReason::OutputGenerated => return Ok(self.cpu.get_last_output().cloned()),
+                ExitReason::Halt => return Ok(None),
+                ExitReason::InputRequired => match (Recv { queue: &self.queue }).await {
+                    Some(value) => input = Some(value.to_string()),
+                    None => return Ok(None),
+                },
+            }
+        }
+    }
+
+    /// Why the CPU last stopped running
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        self.exit_reason
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU {
+        self.cpu
+    }
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+struct TaskWaker {
+    id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready.lock().unwrap().push_back(self.id);
+    }
+}
+
+/// Minimal single threaded executor: spawn tasks, then `run` them until none can progress
+#[derive(Default)]
+pub struct Executor {
+    tasks: Vec<Option<Task>>,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor::default()
+    }
+
+    pub fn spawn<F: Future<Output = ()> + 'static>(&mut self, task: F) {
+        self.ready.lock().unwrap().push_back(self.tasks.len());
+        self.tasks.push(Some(Box::pin(task)));
+    }
+
+    /// Poll tasks until every one finished (true) or the remaining ones are all waiting on
+    /// something no task will ever provide (false)
+    pub fn run(&mut self) -> bool {
+        loop {
+            let id = match self.ready.lock().unwrap().pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            let task = match self.tasks[id].as_mut() {
+                Some(task) => task,
+                None => continue,
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                ready: self.ready.clone(),
+            }));
+            if task
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_ready()
+            {
+                self.tasks[id] = None;
+            }
+        }
+        self.tasks.iter().all(Option::is_none)
+    }
+}
+
+/// Run a single future to completion on the current thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_next_output() {
+        // Output input * 2 until the input is 0
+        let mut cpu = AsyncCpu::new(CPU::new(
+            "3,20,1006,20,14,1002,20,2,21,4,21,1105,1,0,99,0,0,0,0,0,0,0",
+        ));
+        cpu.send(4);
+        cpu.send(5);
+        cpu.send(0);
+        let outputs = block_on(async {
+            let mut outputs = vec![];
+            while let Some(value) = cpu.next_output().await.unwrap() {
+                outputs.push(value);
+            }
+            outputs
+        });
+        assert_eq!(outputs, vec![8, 10]);
+        assert_eq!(cpu.exit_reason(), Some(ExitReason::Halt));
+    }
+
+    #[test]
+    fn test_closed_input() {
+        let mut cpu = AsyncCpu::new(CPU::new("3,7,4,7,1105,1,0,0"));
+        let sender = cpu.sender();
+        sender.send(7);
+        drop(sender);
+        let outputs = block_on(async {
+            let mut outputs = vec![];
+            while let Some(value) = cpu.next_output().await.unwrap() {
+                outputs.push(value);
+            }
+            outputs
+        });
+        assert_eq!(outputs, vec![7]);
+        assert_eq!(cpu.exit_reason(), Some(ExitReason::InputRequired));
+    }
+
+    #[test]
+    fn test_executor_pipeline() {
+        // Each stage adds one to what it reads, forever
+        let stage = || AsyncCpu::new(CPU::new("3,11,1001,11,1,11,4,11,1105,1,0,0"));
+        let mut first = stage();
+        let mut second = stage();
+        let to_second = second.sender();
+        let input = first.sender();
+        let result = Rc::new(Cell::new(0));
+        let seen = result.clone();
+
+        let mut executor = Executor::new();
+        executor.spawn(async move {
+            while let Some(value) = first.next_output().await.unwrap() {
+                to_second.send(value);
+            }
+        });
+        executor.spawn(async move {
+            while let Some(value) = second.next_output().await.unwrap() {
+                seen.set(value);
+            }
+        });
+        input.send(40);
+        drop(input);
+        assert!(executor.run());
+        assert_eq!(result.get(), 42);
+    }
+
+    #[test]
+    fn test_stuck_tasks() {
+        let mut cpu = AsyncCpu::new(CPU::new("3,0,99"));
+        let sender = cpu.sender();
+        let mut executor = Executor::new();
+        executor.spawn(async move {
+            cpu.next_output().await.unwrap();
+        });
+        assert!(!executor.run());
+        drop(sender);
+    }
+}
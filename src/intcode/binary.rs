@@ -0,0 +1,375 @@
+//! Compact binary container for intcode programs and memory images.
+//!
+//! ```text
+//! magic     "INTC"
+//! version   1 byte, currently 1
+//! metadata  varint entry count, then per entry a tag byte, a varint payload length and
+//!           the payload, so readers can skip tags they do not know
+//!             1: suggested memory size, varint
+//!             2: patch, varint address then zigzag varint value
+//! words     varint count, then every word as a zigzag varint
+//! checksum  CRC-32 of everything before it, 4 bytes little endian
+//! ```
+//!
+//! Varints are LEB128: 7 bits per byte, least significant first, high bit set on every
+//! byte but the last. Zigzag maps 0, -1, 1, -2... to 0, 1, 2, 3... so small negative words
+//! stay short.
+
+use std::fmt;
+
+use super::CPU;
+
+pub const MAGIC: &[u8; 4] = b"INTC";
+pub const VERSION: u8 = 1;
+
+const TAG_MEMORY_SIZE: u8 = 1;
+const TAG_PATCH: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinaryError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The data ends in the middle of the named part
+    Truncated(&'static str),
+    /// A varint longer than 64 bits
+    Overflow,
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// A known metadata entry whose payload does not match its length
+    BadMetadata(u8),
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryError::BadMagic => write!(f, "not an intcode binary"),
+            BinaryError::UnsupportedVersion(version) => {
+                write!(f, "unsupported intcode binary version {}", version)
+            }
+            BinaryError::Truncated(part) => write!(f, "truncated {}", part),
+            BinaryError::Overflow => write!(f, "varint does not fit in 64 bits"),
+            BinaryError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum mismatch, expected {:08x} but found {:08x}",
+                expected, found
+            ),
+            BinaryError::BadMetadata(tag) => write!(f, "malformed metadata entry {}", tag),
+        }
+    }
+}
+
+impl std::error::Error for BinaryError {}
+
+/// A program or memory image with the metadata needed to run it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Image {
+    pub words: Vec<isize>,
+    /// Memory size the program expects, at least the number of words
+    pub memory_size: Option<usize>,
+    /// Values to store before running, such as day 2's noun and verb
+    pub patches: Vec<(usize, isize)>,
+}
+
+impl Image {
+    pub fn new(words: Vec<isize>) -> Image {
+        Image {
+            words,
+            ..Image::default()
+        }
+    }
+
+    /// Whether `bytes` start like an intcode binary rather than text
+    pub fn is_binary(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+
+        let mut entries: Vec<(u8, Vec<u8>)> = vec![];
+        if let Some(size) = self.memory_size {
+            let mut payload = vec![];
+            write_varint(&mut payload, size as u64);
+            entries.push((TAG_MEMORY_SIZE, payload));
+        }
+        for (address, value) in &self.patches {
+            let mut payload = vec![];
+            write_varint(&mut payload, *address as u64);
+            write_varint(&mut payload, zigzag(*value));
+            entries.push((TAG_PATCH, payload));
+        }
+        write_varint(&mut bytes, entries.len() as u64);
+        for (tag, payload) in entries {
+            bytes.push(tag);
+            write_varint(&mut bytes, payload.len() as u64);
+            bytes.extend(payload);
+        }
+
+        write_varint(&mut bytes, self.words.len() as u64);
+        for word in &self.words {
+            write_varint(&mut bytes, zigzag(*word));
+        }
+        let checksum = crc32(&bytes);
+        bytes.extend(&checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Image, BinaryError> {
+        if !Image::is_binary(bytes) {
+            return Err(BinaryError::BadMagic);
+        }
+        if bytes.len() < MAGIC.len() + 1 + 4 {
+            return Err(BinaryError::Truncated("header"));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        let found = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+        let expected = crc32(body);
+        if found != expected {
+            return Err(BinaryError::ChecksumMismatch { expected, found });
+        }
+        let version = body[MAGIC.len()];
+        if version != VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+
+        let mut reader = Reader {
+            bytes: &body[MAGIC.len() + 1..],
+        };
+        let mut image = Image::default();
+        let entries = reader.varint("metadata")?;
+        for _ in 0..entries {
+            let tag = reader.byte("metadata")?;
+            let length = reader.varint("metadata")? as usize;
+            let mut payload = Reader {
+                bytes: reader.take(length, "metadata")?,
+            };
+            let bad = |_| BinaryError::BadMetadata(tag);
+            match tag {
+                TAG_MEMORY_SIZE => {
+                    image.memory_size = Some(payload.varint("metadata").map_err(bad)? as usize)
+                }
+                TAG_PATCH => {
+                    let address = payload.varint("metadata").map_err(bad)? as usize;
+                    let value = unzigzag(payload.varint("metadata").map_err(bad)?);
+                    image.patches.push((address, value));
+                }
+                _ => continue,
+            }
+            if !payload.bytes.is_empty() {
+                return Err(BinaryError::BadMetadata(tag));
+            }
+        }
+
+        let count = reader.varint("words")? as usize;
+        // Every word takes at least a byte, which bounds the allocation for corrupt counts
+        image.words = Vec::with_capacity(count.min(reader.bytes.len()));
+        for _ in 0..count {
+            image.words.push(unzigzag(reader.varint("words")?));
+        }
+        let size = image.memory_size.unwrap_or(0).max(image.words.len());
+        if image.patches.iter().any(|(address, _)| *address >= size) {
+            return Err(BinaryError::BadMetadata(TAG_PATCH));
+        }
+        Ok(image)
+    }
+
+    /// A CPU loaded with the words, grown to the memory size and patched
+    pub fn to_cpu(&self) -> CPU {
+        let mut cpu = CPU::from_memory(self.words.clone());
+        if let Some(size) = self.memory_size {
+            if size > self.words.len() {
+                cpu.set_memory_size(size);
+            }
+        }
+        for (address, value) in &self.patches {
+            cpu.set_memory(*address, *value);
+        }
+        cpu
+    }
+}
+
+impl CPU {
+    /// Load a program saved with `Image::to_bytes`
+    pub fn from_binary(bytes: &[u8]) -> Result<CPU, BinaryError> {
+        Ok(Image::from_bytes(bytes)?.to_cpu())
+    }
+
+    /// The current memory as an image of the same size. Trailing zeros are left to the
+    /// memory size instead of being stored.
+    pub fn to_image(&self) -> Image {
+        let mut words = self.memory.to_vec();
+        let used = words
+            .iter()
+            .rposition(|word| *word != 0)
+            .map_or(0, |last| last + 1);
+        words.truncate(used);
+        Image {
+            words,
+            memory_size: Some(self.memory.len()),
+            patches: vec![],
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self, part: &'static str) -> Result<u8, BinaryError> {
+        Ok(self.take(1, part)?[0])
+    }
+
+    fn take(&mut self, length: usize, part: &'static str) -> Result<&'a [u8], BinaryError> {
+        if length > self.bytes.len() {
+            return Err(BinaryError::Truncated(part));
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self, part: &'static str) -> Result<u64, BinaryError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte(part)?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                return Err(BinaryError::Overflow);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(BinaryError::Overflow)
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn zigzag(value: isize) -> u64 {
+    let value = value as i64;
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> isize {
+    ((value >> 1) as i64 ^ -((value & 1) as i64)) as isize
+}
+
+/// CRC-32 with the IEEE polynomial, as used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode::loader::parse_program;
+
+    #[test]
+    fn test_varints() {
+        for value in [0, 1, -1, 63, -64, 64, 1 << 40, isize::MAX, isize::MIN] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+            let mut bytes = vec![];
+            write_varint(&mut bytes, zigzag(value));
+            let mut reader = Reader { bytes: &bytes };
+            assert_eq!(reader.varint("words"), Ok(zigzag(value)));
+            assert!(reader.bytes.is_empty());
+        }
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let words = parse_program(include_str!("../../input/2019/day9.txt")).unwrap();
+        let image = Image {
+            words,
+            memory_size: Some(4096),
+            patches: vec![(1, 12), (2, -2)],
+        };
+        let bytes = image.to_bytes();
+        assert!(bytes.len() < include_str!("../../input/2019/day9.txt").len());
+        assert_eq!(Image::from_bytes(&bytes), Ok(image.clone()));
+
+        let cpu = CPU::from_binary(&bytes).unwrap();
+        assert_eq!(cpu.memory().len(), 4096);
+        assert_eq!(cpu.get_memory(2), -2);
+        let snapshot = Image::from_bytes(&cpu.to_image().to_bytes()).unwrap();
+        assert_eq!(snapshot.to_cpu().memory(), cpu.memory());
+    }
+
+    #[test]
+    fn test_run_from_binary() {
+        let mut cpu = CPU::new("104,7,99");
+        cpu.set_memory_size(10);
+        assert_eq!(cpu.to_image().words, vec![104, 7, 99]);
+        let bytes = cpu.to_image().to_bytes();
+        let mut loaded = CPU::from_reader(&bytes[..]).expect("Binary programs load too");
+        assert_eq!(loaded.memory().len(), 10);
+        loaded.run(None).unwrap();
+        assert_eq!(loaded.get_output(), vec![7]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = Image::new(vec![1, 2, 3]).to_bytes();
+        assert_eq!(Image::from_bytes(b"1,2,3"), Err(BinaryError::BadMagic));
+
+        let mut corrupt = bytes.clone();
+        corrupt[7] ^= 1;
+        assert!(matches!(
+            Image::from_bytes(&corrupt),
+            Err(BinaryError::ChecksumMismatch { .. })
+        ));
+
+        let mut future = bytes.clone();
+        future[4] = 2;
+        let checksum = crc32(&future[..future.len() - 4]);
+        let length = future.len();
+        future[length - 4..].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            Image::from_bytes(&future),
+            Err(BinaryError::UnsupportedVersion(2))
+        );
+
+        let patched = Image {
+            patches: vec![(3, 1)],
+            ..Image::new(vec![1, 2, 3])
+        };
+        assert_eq!(
+            Image::from_bytes(&patched.to_bytes()),
+            Err(BinaryError::BadMetadata(TAG_PATCH))
+        );
+
+        // Drop the last word and fix up the checksum
+        let mut short = bytes[..bytes.len() - 5].to_vec();
+        let checksum = crc32(&short);
+        short.extend(&checksum.to_le_bytes());
+        assert_eq!(
+            Image::from_bytes(&short),
+            Err(BinaryError::Truncated("words"))
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! A shadow call stack rebuilt from the way compiled intcode calls functions. The relative
+//! base is the stack pointer: a caller stores the return address in `rb[0]` and jumps to
+//! the function, which moves the base past its frame with `arb`, moves it back before it
+//! returns, and returns by jumping to `rb[0]`.
+//!
+//! So a taken jump from `ip` while `rb[0]` holds the address of the next instruction is a
+//! call, and a taken jump to the return address of a frame on the stack, with the base
+//! back where it was at that call, is a return. A return past frames above it unwinds them
+//! too, for functions that never came back the usual way.
+
+use std::fmt;
+
+use super::CPU;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    /// Address of the jump that made the call
+    pub call_site: usize,
+    /// Address of the function called
+    pub entry: usize,
+    pub return_address: usize,
+    /// Relative base at the call, where the return address is stored
+    pub base: isize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub(crate) fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn jump(&mut self, ip: usize, target: usize, relative_base: isize, stored: Option<isize>) {
+        let returning = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_address == target && frame.base == relative_base);
+        if let Some(index) = returning {
+            self.frames.truncate(index);
+        } else if stored == Some(ip as isize + 3) {
+            self.frames.push(Frame {
+                call_site: ip,
+                entry: target,
+                return_address: ip + 3,
+                base: relative_base,
+            });
+        }
+    }
+}
+
+/// Where the CPU is and the calls that led there
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backtrace {
+    pub ip: usize,
+    /// Calls still in progress, innermost first
+    pub frames: Vec<Frame>,
+}
+
+impl Backtrace {
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl fmt::Display for Backtrace {
+    /// One line per function like a debugger, named as the decompiler names them
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ip = self.ip;
+        for (index, frame) in self.frames.iter().enumerate() {
+            writeln!(
+                f,
+                "#{} {} in sub_{} (base {})",
+                index, ip, frame.entry, frame.base
+            )?;
+            ip = frame.call_site;
+        }
+        write!(f, "#{} {} in main", self.frames.len(), ip)
+    }
+}
+
+impl CPU {
+    /// Keep a shadow call stack from now on. Calls made before this are not known.
+    pub fn set_call_tracking(&mut self) {
+        self.call_stack = Some(CallStack::default());
+    }
+
+    pub fn clear_call_tracking(&mut self) {
+        self.call_stack = None;
+    }
+
+    /// The current call stack, if call tracking is on. After a fault the instruction
+    /// pointer is still on the instruction that failed.
+    pub fn backtrace(&self) -> Option<Backtrace> {
+        self.call_stack.as_ref().map(|stack| Backtrace {
+            ip: self.instruction_pointer,
+            frames: stack.frames.iter().rev().cloned().collect(),
+        })
+    }
+
+    /// Follow a jump from `ip` to `target` that was taken
+    pub(crate) fn record_jump(&mut self, ip: usize, target: usize) {
+        if self.call_stack.is_none() {
+            return;
+        }
+        let base = self.relative_base;
+        let stored = Some(base)
+            .filter(|base| *base >= 0 && (*base as usize) < self.memory.len())
+            .map(|base| self.memory[base as usize]);
+        if let Some(stack) = self.call_stack.as_mut() {
+            stack.jump(ip, target, base, stored);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CpuError, ExitReason};
+    use super::*;
+
+    /// `main` calls the function at 12, which calls the function at 26
+    fn nested(callee: &str) -> CPU {
+        let mut cpu = CPU::new(&format!(
+            "109,100,21101,9,0,0,1105,1,12,99,0,0,\
+             109,2,21101,21,0,0,1105,1,26,109,-2,2105,1,0,{}",
+            callee
+        ));
+        cpu.set_memory_size(110);
+        cpu.set_call_tracking();
+        cpu
+    }
+
+    #[test]
+    fn test_calls_and_returns() {
+        let mut cpu = nested("104,7,2105,1,0");
+        cpu.set_exit_on_output();
+        assert_eq!(cpu.run(None), Ok(ExitReason::OutputGenerated));
+        let backtrace = cpu.backtrace().unwrap();
+        assert_eq!(backtrace.ip, 28);
+        assert_eq!(
+            backtrace.frames,
+            vec![
+                Frame {
+                    call_site: 18,
+                    entry: 26,
+                    return_address: 21,
+                    base: 102,
+                },
+                Frame {
+                    call_site: 6,
+                    entry: 12,
+                    return_address: 9,
+                    base: 100,
+                },
+            ]
+        );
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(cpu.backtrace().unwrap().depth(), 0);
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut cpu = nested("98");
+        assert_eq!(cpu.run(None), Err(CpuError::InvalidOpcode(98, 26)));
+        assert_eq!(
+            cpu.backtrace().unwrap().to_string(),
+            "#0 26 in sub_26 (base 102)\n#1 18 in sub_12 (base 100)\n#2 6 in main"
+        );
+    }
+
+    #[test]
+    fn test_loops_are_not_calls() {
+        // A countdown loop, with the relative base left at 0
+        let mut cpu = CPU::new("1001,9,-1,9,1005,9,0,99,0,3");
+        cpu.set_call_tracking();
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(cpu.backtrace().unwrap().depth(), 0);
+        assert_eq!(CPU::new("99").backtrace(), None);
+    }
+
+    #[test]
+    fn test_puzzle_program() {
+        // The BOOST program recurses, and every call has returned by the time it halts
+        let mut cpu = CPU::new(include_str!("../../input/2019/day9.txt"));
+        cpu.set_memory_size(4096);
+        cpu.set_call_tracking();
+        let mut deepest = 0;
+        let mut limit = 0;
+        loop {
+            limit += 1000;
+            cpu.set_step_limit(limit);
+            match cpu.run(Some("2")) {
+                Err(CpuError::StepLimitReached(_)) => {
+                    deepest = deepest.max(cpu.backtrace().unwrap().depth())
+                }
+                result => {
+                    assert_eq!(result, Ok(ExitReason::Halt));
+                    break;
+                }
+            }
+        }
+        assert!(deepest > 10);
+        assert_eq!(cpu.backtrace().unwrap().depth(), 0);
+    }
+}
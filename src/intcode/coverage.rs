@@ -0,0 +1,264 @@
+//! Coverage of intcode programs: which addresses ran as instruction heads and which were
+//! read or written as data, with hit counts that can be merged across runs.
+//!
+//! Reports use an lcov-like text format keyed by address instead of line:
+//!
+//! ```text
+//! SF:day5.txt
+//! DA:<address>,<times executed>
+//! DR:<address>,<times read>
+//! DW:<address>,<times written>
+//! LH:<instruction heads executed>
+//! end_of_record
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::decode::{decode, ParameterMode};
+use super::memory::Memory;
+use super::CPU;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    pub executed: BTreeMap<usize, usize>,
+    pub read: BTreeMap<usize, usize>,
+    pub written: BTreeMap<usize, usize>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Count the instruction at `address` along with the data cells it touches
+    pub(crate) fn record(&mut self, memory: &Memory, address: usize, relative_base: isize) {
+        *self.executed.entry(address).or_insert(0) += 1;
+        let op = match memory.decode(address) {
+            Some(op) => op,
+            None => return,
+        };
+        let write_operand = op.write_operand();
+        for (index, operand) in op.operands.iter().enumerate() {
+            let cell = match operand.mode {
+                ParameterMode::Position => operand.raw,
+                ParameterMode::Relative => relative_base + operand.raw,
+                ParameterMode::Immediate => continue,
+            };
+            if cell < 0 {
+                continue;
+            }
+            let counts = if write_operand == Some(index) {
+                &mut self.written
+            } else {
+                &mut self.read
+            };
+            *counts.entry(cell as usize).or_insert(0) += 1;
+        }
+    }
+
+    /// Add the hit counts of `other` to these
+    pub fn merge(&mut self, other: &Coverage) {
+        for (mine, theirs) in [
+            (&mut self.executed, &other.executed),
+            (&mut self.read, &other.read),
+            (&mut self.written, &other.written),
+        ] {
+            for (address, count) in theirs {
+                *mine.entry(*address).or_insert(0) += count;
+            }
+        }
+    }
+
+    pub fn is_executed(&self, address: usize) -> bool {
+        self.executed.contains_key(&address)
+    }
+
+    pub fn to_lcov(&self, source: &str) -> String {
+        let mut report = format!("SF:{}\n", source);
+        for (tag, counts) in [
+            ("DA", &self.executed),
+            ("DR", &self.read),
+            ("DW", &self.written),
+        ] {
+            for (address, count) in counts {
+                let _ = writeln!(report, "{}:{},{}", tag, address, count);
+            }
+        }
+        let _ = writeln!(report, "LH:{}", self.executed.len());
+        report.push_str("end_of_record\n");
+        report
+    }
+
+    /// Read back a report written by `to_lcov`. Every record in it is merged together and
+    /// summary lines are recomputed rather than trusted.
+    pub fn from_lcov(report: &str) -> Result<Coverage, String> {
+        let mut coverage = Coverage::new();
+        for (number, line) in report.lines().enumerate() {
+            let line = line.trim();
+            let (tag, rest) = match line.find(':') {
+                Some(index) => (&line[..index], &line[index + 1..]),
+                None if line.is_empty() || line == "end_of_record" => continue,
+                None => return Err(format!("Line {} : expected TAG:VALUE", number + 1)),
+            };
+            let counts = match tag {
+                "DA" => &mut coverage.executed,
+                "DR" => &mut coverage.read,
+                "DW" => &mut coverage.written,
+                _ => continue,
+            };
+            let mut parts = rest.splitn(2, ',');
+            let invalid = || format!("Line {} : expected {}:ADDRESS,COUNT", number + 1, tag);
+            let address: usize = parts
+                .next()
+                .and_then(|part| part.trim().parse().ok())
+                .ok_or_else(invalid)?;
+            let count: usize = parts
+                .next()
+                .and_then(|part| part.trim().parse().ok())
+                .ok_or_else(invalid)?;
+            *counts.entry(address).or_insert(0) += count;
+        }
+        Ok(coverage)
+    }
+
+    /// Annotated listing of `program`. Executed instructions show how often they ran, `-`
+    /// marks something that decodes as an instruction but never ran, and cells used as data
+    /// show their read and write counts. Decoding is a linear sweep from address 0, so data
+    /// that happens to decode can be listed as unreached code.
+    pub fn listing(&self, program: &[isize]) -> String {
+        let mut listing = String::new();
+        let mut address = 0;
+        while address < program.len() {
+            let is_data = self.read.contains_key(&address) || self.written.contains_key(&address);
+            let op = match self.executed.get(&address) {
+                Some(_) => decode(program, address),
+                None if !is_data => decode(program, address),
+                None => None,
+            };
+            match op {
+                Some(op) => {
+                    let hits = self
+                        .executed
+                        .get(&address)
+                        .map_or("-".to_string(), |count| count.to_string());
+                    let _ = writeln!(listing, "{:>8} | {:>5}: {}", hits, address, op);
+                    address = op.next();
+                }
+                None => {
+                    let reads = self.read.get(&address).cloned().unwrap_or(0);
+                    let writes = self.written.get(&address).cloned().unwrap_or(0);
+                    let _ = write!(listing, "{:>8} | {:>5}: {}", "", address, program[address]);
+                    if is_data {
+                        let _ = write!(listing, "  (r {}, w {})", reads, writes);
+                    }
+                    listing.push('\n');
+                    address += 1;
+                }
+            }
+        }
+        listing
+    }
+}
+
+impl CPU {
+    /// Start counting executed instructions and data accesses from here on
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Coverage::new());
+        }
+    }
+
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Stop counting and hand back what was collected
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode::loader::parse_program;
+
+    fn covered(program: &str, input: &str) -> Coverage {
+        let mut cpu = CPU::new(program);
+        cpu.set_memory_size(4096);
+        cpu.enable_coverage();
+        cpu.run(Some(input)).expect("Program should halt");
+        cpu.take_coverage().unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        // Output 1 when the input is 8, otherwise skip the output
+        let program = "3,13,1008,13,8,14,1005,14,10,99,104,1,99,0,0";
+        let coverage = covered(program, "8");
+        assert_eq!(
+            coverage.executed.keys().cloned().collect::<Vec<_>>(),
+            vec![0, 2, 6, 10, 12]
+        );
+        assert_eq!(coverage.read.get(&13), Some(&1));
+        assert_eq!(coverage.written.get(&13), Some(&1));
+        assert_eq!(coverage.written.get(&14), Some(&1));
+
+        let other = covered(program, "3");
+        assert!(other.is_executed(9) && !other.is_executed(10));
+
+        let mut merged = coverage.clone();
+        merged.merge(&other);
+        assert_eq!(merged.executed.get(&0), Some(&2));
+        assert_eq!(merged.executed.get(&10), Some(&1));
+        assert!(merged.is_executed(9));
+    }
+
+    #[test]
+    fn test_lcov_round_trip() {
+        let coverage = covered("3,13,1008,13,8,14,1005,14,10,99,104,1,99,0,0", "8");
+        let report = coverage.to_lcov("equals_eight");
+        assert!(report.starts_with("SF:equals_eight\nDA:0,1\n"));
+        assert!(report.ends_with("LH:5\nend_of_record\n"));
+        assert_eq!(Coverage::from_lcov(&report), Ok(coverage.clone()));
+
+        let mut doubled = coverage.clone();
+        doubled.merge(&coverage);
+        assert_eq!(Coverage::from_lcov(&report.repeat(2)), Ok(doubled));
+        assert!(Coverage::from_lcov("DA:1").is_err());
+    }
+
+    #[test]
+    fn test_listing() {
+        let source = "3,13,1008,13,8,14,1005,14,10,99,104,1,99,0,0";
+        let listing = covered(source, "3").listing(&parse_program(source).unwrap());
+        assert_eq!(
+            listing,
+            "       1 |     0: in [13]
+       1 |     2: eq [13], 8, [14]
+       1 |     6: jnz [14], 10
+       1 |     9: halt
+       - |    10: out 1
+       - |    12: halt
+         |    13: 0  (r 1, w 1)
+         |    14: 0  (r 1, w 1)
+"
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_inputs() {
+        let program = include_str!("../../input/2019/day5.txt");
+        let air_conditioner = covered(program, "1");
+        let radiator = covered(program, "5");
+        assert!(air_conditioner
+            .executed
+            .keys()
+            .any(|address| !radiator.is_executed(*address)));
+        assert!(radiator
+            .executed
+            .keys()
+            .any(|address| !air_conditioner.is_executed(*address)));
+    }
+}
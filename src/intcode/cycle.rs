@@ -0,0 +1,129 @@
+//! Detection of programs stuck in a loop. If the CPU ever comes back to the exact same state
+//! (memory, instruction pointer, relative base and pending input) it will go round the same
+//! path forever, never halting or asking for more input.
+//!
+//! The memory is hashed incrementally, as the XOR of a hash of every non zero cell, so a
+//! write costs as much to track as it costs to perform, however large the memory. States are
+//! only compared within a stretch of execution that consumes no input: consuming a value or
+//! stopping to ask for more changes the pending input, so it starts a new history. Two
+//! different states can collide on the same 64 bit hash, which would report a loop where
+//! there is none, but in practice that does not happen.
+
+use std::collections::HashSet;
+
+use super::memory::Memory;
+use super::CPU;
+
+fn mix(mut value: u64) -> u64 {
+    // splitmix64 finalizer
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+/// Hash of one memory cell, zero for cells holding zero so growing the memory is free
+fn cell_hash(address: usize, value: isize) -> u64 {
+    if value == 0 {
+        0
+    } else {
+        mix(mix(address as u64) ^ value as u64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CycleDetector {
+    interval: usize,
+    memory_hash: u64,
+    seen: HashSet<u64>,
+}
+
+impl CycleDetector {
+    pub(crate) fn new(interval: usize, memory: &Memory) -> CycleDetector {
+        let mut detector = CycleDetector {
+            interval: interval.max(1),
+            memory_hash: 0,
+            seen: HashSet::new(),
+        };
+        detector.rehash(memory);
+        detector
+    }
+
+    /// Hash the whole memory again, for changes not made through `write`
+    pub(crate) fn rehash(&mut self, memory: &Memory) {
+        self.memory_hash = memory
+            .iter()
+            .enumerate()
+            .fold(0, |hash, (address, value)| hash ^ cell_hash(address, value));
+    }
+
+    pub(crate) fn write(&mut self, address: usize, old: isize, new: isize) {
+        self.memory_hash ^= cell_hash(address, old) ^ cell_hash(address, new);
+    }
+
+    /// Forget every state seen so far
+    pub(crate) fn reset(&mut self) {
+        self.seen.clear();
+    }
+
+    /// Record the current state every `interval` steps, true if it was already recorded
+    pub(crate) fn repeats(&mut self, steps: usize, ip: usize, relative_base: isize) -> bool {
+        let phase = steps % self.interval;
+        if phase != 0 {
+            return false;
+        }
+        let state = mix(self.memory_hash ^ mix(ip as u64)) ^ mix(relative_base as u64 ^ !0);
+        !self.seen.insert(state)
+    }
+}
+
+impl CPU {
+    /// Fail with `CpuError::LoopDetected` once the CPU returns to a state it was already in.
+    /// The state is checked every `interval` instructions. Larger intervals remember fewer
+    /// states but can take up to `interval` laps round a loop to notice it.
+    pub fn set_loop_detection(&mut self, interval: usize) {
+        self.cycle_detector = Some(CycleDetector::new(interval, &self.memory));
+    }
+
+    pub fn clear_loop_detection(&mut self) {
+        self.cycle_detector = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CpuError, ExitReason};
+    use super::*;
+
+    #[test]
+    fn test_tight_loop() {
+        let mut cpu = CPU::new("1105,1,0");
+        cpu.set_loop_detection(1);
+        assert_eq!(cpu.run(None), Err(CpuError::LoopDetected(0)));
+    }
+
+    #[test]
+    fn test_loop_with_writes() {
+        // Flip the cell at 9 between 1 and -1 forever
+        let mut cpu = CPU::new("1002,9,-1,9,1105,1,0,0,0,1");
+        cpu.set_loop_detection(3);
+        assert!(matches!(cpu.run(None), Err(CpuError::LoopDetected(_))));
+        assert!(cpu.get_steps() < 20);
+    }
+
+    #[test]
+    fn test_counter_is_not_a_loop() {
+        let mut cpu = CPU::new("1001,7,1,7,1105,1,0,0");
+        cpu.set_loop_detection(1);
+        cpu.set_step_limit(10_000);
+        assert_eq!(cpu.run(None), Err(CpuError::StepLimitReached(0)));
+    }
+
+    #[test]
+    fn test_input_starts_a_new_history() {
+        // Read into 5 and jump back, so the program only ever waits for more input
+        let mut cpu = CPU::new("3,5,1105,1,0,0");
+        cpu.set_loop_detection(1);
+        assert_eq!(cpu.run(Some("1\n1\n1")), Ok(ExitReason::InputRequired));
+        assert_eq!(cpu.run(Some("1")), Ok(ExitReason::InputRequired));
+    }
+}
@@ -0,0 +1,262 @@
+//! Static decoding of instructions straight from a memory image, keeping the raw operands
+//! and their parameter modes instead of resolving them against a running CPU.
+//!
+//! This is the one definition of the instruction set, shared by the CPU and by the
+//! disassembly, analysis and rewriting tools. For any instruction `decode` accepts, `encode`
+//! gives back exactly the words it was decoded from.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParameterMode {
+    Position,
+    Immediate,
+    Relative,
+}
+
+impl ParameterMode {
+    pub fn from_digit(digit: isize) -> Option<ParameterMode> {
+        match digit {
+            0 => Some(ParameterMode::Position),
+            1 => Some(ParameterMode::Immediate),
+            2 => Some(ParameterMode::Relative),
+            _ => None,
+        }
+    }
+
+    pub fn digit(self) -> isize {
+        match self {
+            ParameterMode::Position => 0,
+            ParameterMode::Immediate => 1,
+            ParameterMode::Relative => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Operand {
+    pub mode: ParameterMode,
+    pub raw: isize,
+}
+
+/// A decoded instruction and the address it was decoded from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Op {
+    pub address: usize,
+    pub opcode: isize,
+    pub operands: Vec<Operand>,
+}
+
+impl Op {
+    pub fn next(&self) -> usize {
+        self.address + 1 + self.operands.len()
+    }
+
+    /// Index of the operand this instruction stores its result through
+    pub fn write_operand(&self) -> Option<usize> {
+        match self.opcode {
+            1 | 2 | 7 | 8 => Some(2),
+            3 => Some(0),
+            _ => None,
+        }
+    }
+
+    /// The words this instruction occupies in memory
+    pub fn encode(&self) -> Vec<isize> {
+        let mut word = self.opcode;
+        let mut scale = 100;
+        for operand in &self.operands {
+            word += scale * operand.mode.digit();
+            scale *= 10;
+        }
+        let mut words = vec![word];
+        words.extend(self.operands.iter().map(|operand| operand.raw));
+        words
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self.opcode {
+            1 => "add",
+            2 => "mul",
+            3 => "in",
+            4 => "out",
+            5 => "jnz",
+            6 => "jz",
+            7 => "lt",
+            8 => "eq",
+            9 => "arb",
+            99 => "halt",
+            _ => "???",
+        }
+    }
+
+    /// Value of an operand when it does not depend on memory
+    pub fn constant(&self, index: usize) -> Option<isize> {
+        match self.operands.get(index) {
+            Some(Operand {
+                mode: ParameterMode::Immediate,
+                raw,
+            }) => Some(*raw),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            ParameterMode::Position => write!(f, "[{}]", self.raw),
+            ParameterMode::Immediate => write!(f, "{}", self.raw),
+            ParameterMode::Relative => write!(f, "[rb{:+}]", self.raw),
+        }
+    }
+}
+
+/// Assembly style listing, `[n]` for position mode and `[rb+n]` for relative mode
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if index == 0 { " " } else { ", " }, operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of operands taken by `opcode`, or None if it is not an instruction
+pub fn arity(opcode: isize) -> Option<usize> {
+    match opcode {
+        1 | 2 | 7 | 8 => Some(3),
+        3 | 4 | 9 => Some(1),
+        5 | 6 => Some(2),
+        99 => Some(0),
+        _ => None,
+    }
+}
+
+/// Decode the instruction at `address`. Fails on unknown opcodes, unknown modes, mode
+/// digits past the last operand and instructions running off the end of memory.
+pub fn decode(memory: &[isize], address: usize) -> Option<Op> {
+    decode_with(|address| memory.get(address).cloned(), address)
+}
+
+/// `decode` for memories that are not a plain slice
+pub(crate) fn decode_with(read: impl Fn(usize) -> Option<isize>, address: usize) -> Option<Op> {
+    let word = read(address)?;
+    if word < 0 {
+        return None;
+    }
+    decode_operands(read, address, arity(word % 100)?)
+}
+
+/// Decode the instruction at `address` as taking `arity` operands, whatever its opcode
+pub(crate) fn decode_operands(
+    read: impl Fn(usize) -> Option<isize>,
+    address: usize,
+    arity: usize,
+) -> Option<Op> {
+    let word = read(address)?;
+    if word < 0 {
+        return None;
+    }
+    let opcode = word % 100;
+    let mut flags = word / 100;
+    let mut operands = Vec::with_capacity(arity);
+    for offset in 1..=arity {
+        let mode = ParameterMode::from_digit(flags % 10)?;
+        flags /= 10;
+        operands.push(Operand {
+            mode,
+            raw: read(address + offset)?,
+        });
+    }
+    if flags != 0 {
+        return None;
+    }
+
+    Some(Op {
+        address,
+        opcode,
+        operands,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    /// Words that are not instructions, to surround the ones under test
+    const PADDING: [isize; 3] = [-1, 98, 33333];
+
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> isize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 16) as i32 as isize
+        }
+    }
+
+    #[test]
+    fn test_round_trip_every_mode() {
+        let mut random = Lcg(2019);
+        for opcode in [1, 2, 3, 4, 5, 6, 7, 8, 9, 99] {
+            let arity = arity(opcode).unwrap();
+            let modes = [
+                ParameterMode::Position,
+                ParameterMode::Immediate,
+                ParameterMode::Relative,
+            ];
+            for combination in (0..arity).map(|_| modes.iter()).multi_cartesian_product() {
+                for _ in 0..20 {
+                    let op = Op {
+                        address: PADDING.len(),
+                        opcode,
+                        operands: combination
+                            .iter()
+                            .map(|mode| Operand {
+                                mode: **mode,
+                                raw: random.next(),
+                            })
+                            .collect(),
+                    };
+                    let mut memory = PADDING.to_vec();
+                    memory.extend(op.encode());
+                    memory.extend(&PADDING);
+
+                    let decoded = decode(&memory, PADDING.len()).expect("Encoded op decodes");
+                    assert_eq!(decoded, op);
+                    assert_eq!(decoded.encode(), &memory[op.address..op.next()]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_random_words() {
+        let mut random = Lcg(9);
+        let mut decoded = 0;
+        for _ in 0..100_000 {
+            let memory: Vec<isize> = (0..4).map(|_| random.next() % 30_000).collect();
+            if let Some(op) = decode(&memory, 0) {
+                assert_eq!(op.encode(), &memory[..op.next()]);
+                decoded += 1;
+            }
+        }
+        assert!(decoded > 0);
+    }
+
+    #[test]
+    fn test_rejects() {
+        assert_eq!(decode(&[], 0), None);
+        assert_eq!(decode(&[-1], 0), None);
+        assert_eq!(decode(&[42], 0), None);
+        assert_eq!(decode(&[301, 0, 0, 0], 0), None);
+        assert_eq!(decode(&[10099], 0), None);
+        assert_eq!(decode(&[1, 0, 0], 0), None);
+    }
+}
@@ -0,0 +1,739 @@
+//! Recover structured pseudo-Rust from an intcode program.
+//!
+//! Code is found by walking every path from address 0 and following constant jump targets,
+//! so data stored between functions never gets decoded. On top of that:
+//!
+//! * An unconditional jump whose return address was just stored in `rb[0]` is a call, and
+//!   its target starts a new function.
+//! * A jump to `rb[0]` after the frame is popped is a return.
+//! * `AdjustRelativeBase` at the start of a function is its frame size. Relative slots
+//!   below it are locals (`local1`, ...), the rest belong to the next call (`out0` is the
+//!   callee's return address, `out1`... its arguments and results).
+//! * Backward jumps become `loop`s and forward conditional jumps become `if`/`else`.
+//!   Anything that does not nest is left as a `goto`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use super::decode::{decode, Op, Operand, ParameterMode};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+    Next,
+    Jump(usize),
+    Branch(usize),
+    Call(usize),
+    Return,
+    Indirect,
+    Halt,
+}
+
+#[derive(Debug, Clone)]
+struct Cond {
+    lhs: String,
+    op: &'static str,
+    rhs: String,
+}
+
+impl Cond {
+    fn negate(&self) -> Cond {
+        let op = match self.op {
+            "==" => "!=",
+            "!=" => "==",
+            "<" => ">=",
+            ">=" => "<",
+            other => other,
+        };
+        Cond {
+            lhs: self.lhs.clone(),
+            op,
+            rhs: self.rhs.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.lhs, self.op, self.rhs)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Line(String),
+    Label(usize),
+    If {
+        cond: Cond,
+        then: Vec<Stmt>,
+        otherwise: Vec<Stmt>,
+    },
+    Loop(Vec<Stmt>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LoopContext {
+    header: usize,
+    exit: usize,
+}
+
+/// A function recovered from the program
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub entry: usize,
+    pub name: String,
+    /// Size of the frame pushed by the prologue, 0 if there is none
+    pub frame_size: isize,
+    /// Frame slots read before they are written, in slot order
+    pub params: Vec<isize>,
+    ops: BTreeMap<usize, Op>,
+    flows: HashMap<usize, Flow>,
+    /// Relative base at each instruction, relative to the one at entry
+    frames: HashMap<usize, Option<isize>>,
+    /// Instructions folded into their surroundings (prologue, epilogue, return address stores)
+    hidden: HashSet<usize>,
+    body: Vec<Stmt>,
+}
+
+/// The result of decompiling a program: its functions, `main` first
+#[derive(Debug, Clone)]
+pub struct Decompilation {
+    pub functions: Vec<Function>,
+}
+
+pub fn decompile(program: &[isize]) -> Decompilation {
+    let mut functions: BTreeMap<usize, Function> = BTreeMap::new();
+    let mut pending = vec![0];
+    while let Some(entry) = pending.pop() {
+        if functions.contains_key(&entry) {
+            continue;
+        }
+        let function = discover(program, entry);
+        pending.extend(function.flows.values().filter_map(|flow| match flow {
+            Flow::Call(target) => Some(*target),
+            _ => None,
+        }));
+        functions.insert(entry, function);
+    }
+
+    let params: HashMap<usize, isize> = functions
+        .values()
+        .map(|function| (function.entry, function.params.last().cloned().unwrap_or(0)))
+        .collect();
+    for function in functions.values_mut() {
+        let structurer = Structurer {
+            function,
+            arities: &params,
+            labels: RefCell::new(BTreeSet::new()),
+        };
+        // The first pass finds which gotos survive structuring, the second places their labels
+        structurer.structure(0, structurer.addresses().len(), None);
+        let body = structurer.structure(0, structurer.addresses().len(), None);
+        function.body = body;
+    }
+
+    Decompilation {
+        functions: functions.into_values().collect(),
+    }
+}
+
+fn discover(program: &[isize], entry: usize) -> Function {
+    let mut function = Function {
+        entry,
+        name: if entry == 0 {
+            "main".to_string()
+        } else {
+            format!("sub_{}", entry)
+        },
+        frame_size: 0,
+        params: vec![],
+        ops: BTreeMap::new(),
+        flows: HashMap::new(),
+        frames: HashMap::new(),
+        hidden: HashSet::new(),
+        body: vec![],
+    };
+
+    if entry != 0 {
+        if let Some(op) = decode(program, entry) {
+            if let (9, Some(size)) = (op.opcode, op.constant(0)) {
+                if size > 0 {
+                    function.frame_size = size;
+                    function.hidden.insert(entry);
+                }
+            }
+        }
+    }
+
+    // The return address stored in rb[0] on the current path, and the instruction that did it
+    type ReturnStore = Option<(isize, usize)>;
+    let mut stack: Vec<(usize, Option<isize>, ReturnStore)> = vec![(entry, Some(0), None)];
+    while let Some((address, frame, return_store)) = stack.pop() {
+        if function.ops.contains_key(&address) {
+            continue;
+        }
+        let op = match decode(program, address) {
+            Some(op) => op,
+            None => {
+                function.flows.insert(address, Flow::Halt);
+                continue;
+            }
+        };
+
+        let mut next_frame = frame;
+        let mut next_store = return_store;
+        match op.opcode {
+            9 => next_frame = frame.and_then(|frame| op.constant(0).map(|delta| frame + delta)),
+            1 | 2 => {
+                let destination = op.operands[2];
+                if destination.mode == ParameterMode::Relative && destination.raw == 0 {
+                    next_store = match (op.constant(0), op.constant(1)) {
+                        (Some(left), Some(right)) if op.opcode == 1 => {
+                            Some((left + right, address))
+                        }
+                        (Some(left), Some(right)) => Some((left * right, address)),
+                        _ => None,
+                    };
+                }
+            }
+            _ => {}
+        }
+
+        let flow = classify(&op, return_store);
+        if let Flow::Call(_) = flow {
+            if let Some((_, store)) = return_store {
+                function.hidden.insert(store);
+            }
+        }
+        match flow {
+            Flow::Next => stack.push((op.next(), next_frame, next_store)),
+            Flow::Jump(target) => stack.push((target, next_frame, None)),
+            Flow::Branch(target) => {
+                stack.push((target, next_frame, None));
+                stack.push((op.next(), next_frame, next_store));
+            }
+            Flow::Call(_) => stack.push((op.next(), next_frame, None)),
+            Flow::Return | Flow::Indirect | Flow::Halt => {}
+        }
+        function.frames.insert(address, frame);
+        function.flows.insert(address, flow);
+        function.ops.insert(address, op);
+    }
+
+    // The epilogue that pops the frame right before returning
+    let returns: Vec<usize> = function
+        .flows
+        .iter()
+        .filter(|(_, flow)| **flow == Flow::Return)
+        .map(|(address, _)| *address)
+        .collect();
+    for address in returns {
+        if let Some((_, previous)) = function.ops.range(..address).next_back() {
+            if previous.next() == address
+                && previous.opcode == 9
+                && previous.constant(0) == Some(-function.frame_size)
+                && function.frame_size > 0
+            {
+                function.hidden.insert(previous.address);
+            }
+        }
+    }
+
+    function.params = find_params(&function);
+    function
+}
+
+fn classify(op: &Op, return_store: Option<(isize, usize)>) -> Flow {
+    match op.opcode {
+        99 => Flow::Halt,
+        5 | 6 => {
+            let always = match op.constant(0) {
+                Some(value) => (op.opcode == 5) == (value != 0),
+                None => false,
+            };
+            let never = op.constant(0).is_some() && !always;
+            if never {
+                return Flow::Next;
+            }
+            match op.operands[1] {
+                Operand {
+                    mode: ParameterMode::Immediate,
+                    raw,
+                } if raw >= 0 => {
+                    let target = raw as usize;
+                    if !always {
+                        Flow::Branch(target)
+                    } else if return_store.map(|(value, _)| value) == Some(op.next() as isize) {
+                        Flow::Call(target)
+                    } else {
+                        Flow::Jump(target)
+                    }
+                }
+                Operand {
+                    mode: ParameterMode::Relative,
+                    raw: 0,
+                } if always => Flow::Return,
+                _ => Flow::Indirect,
+            }
+        }
+        _ => Flow::Next,
+    }
+}
+
+fn find_params(function: &Function) -> Vec<isize> {
+    if function.frame_size == 0 {
+        return vec![];
+    }
+    let mut written = HashSet::new();
+    let mut params = BTreeSet::new();
+    for (address, op) in &function.ops {
+        let frame = match function.frames.get(address) {
+            Some(Some(frame)) => *frame,
+            _ => continue,
+        };
+        let slot = |operand: &Operand| match operand.mode {
+            ParameterMode::Relative => {
+                let slot = frame + operand.raw;
+                if slot > 0 && slot < function.frame_size {
+                    Some(slot)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let (reads, writes): (Vec<&Operand>, Vec<&Operand>) = match op.opcode {
+            1 | 2 | 7 | 8 => (op.operands[..2].iter().collect(), vec![&op.operands[2]]),
+            3 => (vec![], vec![&op.operands[0]]),
+            _ => (op.operands.iter().collect(), vec![]),
+        };
+        for read in reads.into_iter().filter_map(slot) {
+            if !written.contains(&read) {
+                params.insert(read);
+            }
+        }
+        written.extend(writes.into_iter().filter_map(slot));
+    }
+    params.into_iter().collect()
+}
+
+struct Structurer<'a> {
+    function: &'a Function,
+    arities: &'a HashMap<usize, isize>,
+    labels: RefCell<BTreeSet<usize>>,
+}
+
+impl<'a> Structurer<'a> {
+    fn addresses(&self) -> Vec<usize> {
+        self.function.ops.keys().cloned().collect()
+    }
+
+    /// Index of `address` in `addresses`, where one past the last instruction counts as the end
+    fn index_of(&self, addresses: &[usize], address: usize) -> Option<usize> {
+        match addresses.binary_search(&address) {
+            Ok(index) => Some(index),
+            Err(index) if index == addresses.len() => {
+                let last = self.function.ops.values().next_back()?;
+                if last.next() == address {
+                    Some(index)
+                } else {
+                    None
+                }
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn end_of(&self, addresses: &[usize], index: usize) -> usize {
+        match addresses.get(index) {
+            Some(address) => *address,
+            None => self
+                .function
+                .ops
+                .values()
+                .next_back()
+                .map(|op| op.next())
+                .unwrap_or(0),
+        }
+    }
+
+    fn structure(&self, low: usize, high: usize, context: Option<LoopContext>) -> Vec<Stmt> {
+        let addresses = self.addresses();
+        let mut statements = vec![];
+        let mut index = low;
+
+        while index < high {
+            let address = addresses[index];
+            let is_header = context.map(|context| context.header) == Some(address) && index == low;
+            if self.labels.borrow().contains(&address) && !is_header {
+                statements.push(Stmt::Label(address));
+            }
+
+            let latch = if is_header {
+                None
+            } else {
+                (index..high).rev().find(|&latch| {
+                    match self.function.flows.get(&addresses[latch]) {
+                        Some(Flow::Jump(target)) | Some(Flow::Branch(target)) => *target == address,
+                        _ => false,
+                    }
+                })
+            };
+
+            if let Some(latch) = latch {
+                let latch_address = addresses[latch];
+                let inner = LoopContext {
+                    header: address,
+                    exit: self.end_of(&addresses, latch + 1),
+                };
+                let mut body = self.structure(index, latch, Some(inner));
+                if let Some(Flow::Branch(_)) = self.function.flows.get(&latch_address) {
+                    body.push(Stmt::If {
+                        cond: self.condition(latch_address).negate(),
+                        then: vec![Stmt::Line("break".to_string())],
+                        otherwise: vec![],
+                    });
+                }
+                statements.push(Stmt::Loop(body));
+                index = latch + 1;
+                continue;
+            }
+
+            let flow = self.function.flows[&address];
+            match flow {
+                Flow::Branch(target) => {
+                    let cond = self.condition(address);
+                    if let Some(jump) = self.loop_jump(target, context) {
+                        statements.push(Stmt::If {
+                            cond,
+                            then: vec![jump],
+                            otherwise: vec![],
+                        });
+                    } else if let Some(target_index) = self
+                        .index_of(&addresses, target)
+                        .filter(|&target_index| target_index > index && target_index <= high)
+                    {
+                        // A jump at the end of the then block over the else block
+                        let jump_over = if target_index > index + 1 {
+                            match self.function.flows.get(&addresses[target_index - 1]) {
+                                Some(Flow::Jump(end)) if *end > target => self
+                                    .index_of(&addresses, *end)
+                                    .filter(|&end_index| end_index <= high),
+                                _ => None,
+                            }
+                        } else {
+                            None
+                        };
+                        match jump_over {
+                            Some(end_index) => {
+                                statements.push(Stmt::If {
+                                    cond: cond.negate(),
+                                    then: self.structure(index + 1, target_index - 1, context),
+                                    otherwise: self.structure(target_index, end_index, context),
+                                });
+                                index = end_index;
+                            }
+                            None => {
+                                statements.push(Stmt::If {
+                                    cond: cond.negate(),
+                                    then: self.structure(index + 1, target_index, context),
+                                    otherwise: vec![],
+                                });
+                                index = target_index;
+                            }
+                        }
+                        continue;
+                    } else {
+                        statements.push(Stmt::If {
+                            cond,
+                            then: vec![self.goto(target)],
+                            otherwise: vec![],
+                        });
+                    }
+                }
+                Flow::Jump(target) => {
+                    if let Some(jump) = self.loop_jump(target, context) {
+                        statements.push(jump);
+                    } else if target != self.end_of(&addresses, index + 1) {
+                        statements.push(self.goto(target));
+                    }
+                }
+                _ => {
+                    if let Some(line) = self.statement(address) {
+                        statements.push(Stmt::Line(line));
+                    }
+                }
+            }
+            index += 1;
+        }
+
+        statements
+    }
+
+    fn loop_jump(&self, target: usize, context: Option<LoopContext>) -> Option<Stmt> {
+        let context = context?;
+        if target == context.header {
+            Some(Stmt::Line("continue".to_string()))
+        } else if target == context.exit {
+            Some(Stmt::Line("break".to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn goto(&self, target: usize) -> Stmt {
+        self.labels.borrow_mut().insert(target);
+        Stmt::Line(format!("goto L_{}", target))
+    }
+
+    fn name(&self, address: usize, operand: &Operand, write: bool) -> String {
+        match operand.mode {
+            ParameterMode::Immediate if !write => operand.raw.to_string(),
+            ParameterMode::Position | ParameterMode::Immediate => format!("mem[{}]", operand.raw),
+            ParameterMode::Relative => {
+                let frame = match self.function.frames.get(&address) {
+                    Some(Some(frame)) => *frame,
+                    _ => return format!("rb[{}]", operand.raw),
+                };
+                let size = self.function.frame_size;
+                if size > 0 {
+                    let slot = frame + operand.raw;
+                    if slot == 0 {
+                        "ret_addr".to_string()
+                    } else if slot > 0 && slot < size {
+                        format!("local{}", slot)
+                    } else if slot >= size {
+                        format!("out{}", slot - size)
+                    } else {
+                        format!("rb[{}]", operand.raw)
+                    }
+                } else if operand.raw >= 0 {
+                    format!("out{}", operand.raw)
+                } else {
+                    format!("rb[{}]", operand.raw)
+                }
+            }
+        }
+    }
+
+    fn condition(&self, address: usize) -> Cond {
+        let op = &self.function.ops[&address];
+        let tested = self.name(address, &op.operands[0], false);
+        let jump_if_true = op.opcode == 5;
+
+        // Fold a compare into the jump that tests its result
+        if let Some((_, previous)) = self.function.ops.range(..address).next_back() {
+            if previous.next() == address
+                && (previous.opcode == 7 || previous.opcode == 8)
+                && self.name(previous.address, &previous.operands[2], true) == tested
+            {
+                let compare = Cond {
+                    lhs: self.name(previous.address, &previous.operands[0], false),
+                    op: if previous.opcode == 7 { "<" } else { "==" },
+                    rhs: self.name(previous.address, &previous.operands[1], false),
+                };
+                return if jump_if_true {
+                    compare
+                } else {
+                    compare.negate()
+                };
+            }
+        }
+
+        Cond {
+            lhs: tested,
+            op: if jump_if_true { "!=" } else { "==" },
+            rhs: "0".to_string(),
+        }
+    }
+
+    fn statement(&self, address: usize) -> Option<String> {
+        if self.function.hidden.contains(&address) {
+            return None;
+        }
+        let op = &self.function.ops[&address];
+        let read = |index: usize| self.name(address, &op.operands[index], false);
+        let write = |index: usize| self.name(address, &op.operands[index], true);
+
+        let line = match op.opcode {
+            1 => {
+                let value = match (op.constant(0), op.constant(1)) {
+                    (Some(0), _) => read(1),
+                    (_, Some(0)) => read(0),
+                    (_, Some(right)) if right < 0 => format!("{} - {}", read(0), -right),
+                    _ => format!("{} + {}", read(0), read(1)),
+                };
+                format!("{} = {}", write(2), value)
+            }
+            2 => {
+                let value = match (op.constant(0), op.constant(1)) {
+                    (Some(1), _) => read(1),
+                    (_, Some(1)) => read(0),
+                    (Some(-1), _) => format!("-{}", read(1)),
+                    (_, Some(-1)) => format!("-{}", read(0)),
+                    _ => format!("{} * {}", read(0), read(1)),
+                };
+                format!("{} = {}", write(2), value)
+            }
+            3 => format!("{} = input()", write(0)),
+            4 => format!("output({})", read(0)),
+            7 => format!("{} = ({} < {}) as isize", write(2), read(0), read(1)),
+            8 => format!("{} = ({} == {}) as isize", write(2), read(0), read(1)),
+            9 => format!("rb += {}", read(0)),
+            99 => "halt()".to_string(),
+            5 | 6 => match self.function.flows[&address] {
+                Flow::Call(target) => {
+                    let arity = self.arities.get(&target).cloned().unwrap_or(0);
+                    let args: Vec<String> =
+                        (1..=arity).map(|slot| format!("out{}", slot)).collect();
+                    format!("sub_{}({})", target, args.join(", "))
+                }
+                Flow::Return => "return".to_string(),
+                Flow::Indirect => format!("goto *{}", read(1)),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(line)
+    }
+}
+
+fn write_block(f: &mut fmt::Formatter<'_>, statements: &[Stmt], depth: usize) -> fmt::Result {
+    let indent = "    ".repeat(depth);
+    for statement in statements {
+        match statement {
+            Stmt::Line(line) => writeln!(f, "{}{};", indent, line)?,
+            Stmt::Label(address) => writeln!(f, "{}L_{}:", "    ".repeat(depth - 1), address)?,
+            Stmt::Loop(body) => {
+                writeln!(f, "{}loop {{", indent)?;
+                write_block(f, body, depth + 1)?;
+                writeln!(f, "{}}}", indent)?;
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                writeln!(f, "{}if {} {{", indent, cond)?;
+                write_block(f, then, depth + 1)?;
+                if otherwise.is_empty() {
+                    writeln!(f, "{}}}", indent)?;
+                } else {
+                    writeln!(f, "{}}} else {{", indent)?;
+                    write_block(f, otherwise, depth + 1)?;
+                    writeln!(f, "{}}}", indent)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|slot| format!("local{}", slot))
+            .collect();
+        writeln!(f, "fn {}({}) {{", self.name, params.join(", "))?;
+        write_block(f, &self.body, 1)?;
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Decompilation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, function) in self.functions.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode::loader::parse_program;
+
+    fn decompiled(program: &str) -> String {
+        decompile(&parse_program(program).expect("Valid program")).to_string()
+    }
+
+    #[test]
+    fn test_countdown_loop() {
+        let source = "1101,3,0,20,4,20,1001,20,-1,20,1005,20,4,99";
+        assert_eq!(
+            decompiled(source),
+            "fn main() {
+    mem[20] = 3;
+    loop {
+        output(mem[20]);
+        mem[20] = mem[20] - 1;
+        if mem[20] == 0 {
+            break;
+        }
+    }
+    halt();
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_if_else() {
+        // Outputs 1 if the input is less than 8, otherwise 2
+        let source = "3,30,1007,30,8,31,1006,31,19,104,1,1105,1,21,0,0,0,0,0,104,2,99";
+        assert_eq!(
+            decompiled(source),
+            "fn main() {
+    mem[30] = input();
+    mem[31] = (mem[30] < 8) as isize;
+    if mem[30] < 8 {
+        output(1);
+    } else {
+        output(2);
+    }
+    halt();
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let source = "109,100,21101,5,0,1,21101,13,0,0,1105,1,16,204,1,99,\
+                      109,2,22101,1,-1,-1,109,-2,2105,1,0";
+        assert_eq!(
+            decompiled(source),
+            "fn main() {
+    rb += 100;
+    out1 = 5;
+    sub_16(out1);
+    output(out1);
+    halt();
+}
+
+fn sub_16(local1) {
+    local1 = 1 + local1;
+    return;
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_puzzle_program() {
+        let program = parse_program(include_str!("../../input/2019/day13.txt")).unwrap();
+        let decompilation = decompile(&program);
+        assert_eq!(decompilation.functions[0].name, "main");
+        assert!(decompilation
+            .functions
+            .iter()
+            .any(|function| function.entry == 549));
+        assert!(decompilation.to_string().contains("sub_578("));
+    }
+}
@@ -0,0 +1,234 @@
+//! Compare CPU states, either two at once with `CPU::diff` or over time with a `Scan` that
+//! narrows down which cells hold a value of interest, cheat-engine style.
+
+use std::fmt;
+
+use super::memory::{Memory, PAGE_SIZE};
+use super::CPU;
+
+/// A run of consecutive cells that changed, with their values before and after
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryChange {
+    pub start: usize,
+    pub old: Vec<isize>,
+    pub new: Vec<isize>,
+}
+
+impl MemoryChange {
+    pub fn end(&self) -> usize {
+        self.start + self.old.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuDiff {
+    pub changes: Vec<MemoryChange>,
+    pub instruction_pointer_delta: isize,
+    pub relative_base_delta: isize,
+    /// Outputs produced by the newer state that the older one had not produced
+    pub new_output: Vec<isize>,
+}
+
+impl CpuDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+            && self.instruction_pointer_delta == 0
+            && self.relative_base_delta == 0
+            && self.new_output.is_empty()
+    }
+
+    /// Every changed address on its own, as (address, old, new)
+    pub fn cells(&self) -> impl Iterator<Item = (usize, isize, isize)> + '_ {
+        self.changes.iter().flat_map(|change| {
+            change
+                .old
+                .iter()
+                .zip(change.new.iter())
+                .enumerate()
+                .map(move |(offset, (old, new))| (change.start + offset, *old, *new))
+        })
+    }
+}
+
+impl fmt::Display for CpuDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "IP {:+}, relative base {:+}",
+            self.instruction_pointer_delta, self.relative_base_delta
+        )?;
+        for change in &self.changes {
+            writeln!(
+                f,
+                "[{}..{}] {:?} -> {:?}",
+                change.start,
+                change.end(),
+                change.old,
+                change.new
+            )?;
+        }
+        if !self.new_output.is_empty() {
+            writeln!(f, "Output {:?}", self.new_output)?;
+        }
+        Ok(())
+    }
+}
+
+impl CPU {
+    /// What changed going from `self` to `other`. Cells past the end of the shorter memory
+    /// count as 0, the same value `set_memory_size` fills them with.
+    pub fn diff(&self, other: &CPU) -> CpuDiff {
+        let length = self.memory.len().max(other.memory.len());
+        let mut changes: Vec<MemoryChange> = vec![];
+        let mut address = 0;
+        while address < length {
+            // Pages still shared between the two states cannot hold any changes
+            if address % PAGE_SIZE == 0 && self.memory.same_page(&other.memory, address) {
+                address += PAGE_SIZE;
+                continue;
+            }
+            let old = self.memory.get(address).unwrap_or(0);
+            let new = other.memory.get(address).unwrap_or(0);
+            if old != new {
+                match changes.last_mut() {
+                    Some(change) if change.end() == address => {
+                        change.old.push(old);
+                        change.new.push(new);
+                    }
+                    _ => changes.push(MemoryChange {
+                        start: address,
+                        old: vec![old],
+                        new: vec![new],
+                    }),
+                }
+            }
+            address += 1;
+        }
+
+        let new_output = if other.output.starts_with(&self.output) {
+            other.output[self.output.len()..].to_vec()
+        } else {
+            other.output.clone()
+        };
+
+        CpuDiff {
+            changes,
+            instruction_pointer_delta: other.instruction_pointer as isize
+                - self.instruction_pointer as isize,
+            relative_base_delta: other.relative_base - self.relative_base,
+            new_output,
+        }
+    }
+}
+
+/// How a candidate cell must have changed since the last snapshot to stay a candidate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanFilter {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    IncreasedBy(isize),
+    DecreasedBy(isize),
+    Equals(isize),
+}
+
+impl ScanFilter {
+    fn matches(self, old: isize, new: isize) -> bool {
+        match self {
+            ScanFilter::Changed => old != new,
+            ScanFilter::Unchanged => old == new,
+            ScanFilter::Increased => new > old,
+            ScanFilter::Decreased => new < old,
+            ScanFilter::IncreasedBy(amount) => new.checked_sub(old) == Some(amount),
+            ScanFilter::DecreasedBy(amount) => old.checked_sub(new) == Some(amount),
+            ScanFilter::Equals(value) => new == value,
+        }
+    }
+}
+
+/// Narrow down the addresses holding some value by repeatedly snapshotting and filtering
+#[derive(Debug, Clone)]
+pub struct Scan {
+    snapshot: Memory,
+    candidates: Vec<usize>,
+}
+
+impl Scan {
+    /// Start with every address in `cpu` as a candidate
+    pub fn new(cpu: &CPU) -> Scan {
+        Scan {
+            snapshot: cpu.memory.clone(),
+            candidates: (0..cpu.memory.len()).collect(),
+        }
+    }
+
+    /// Keep the candidates whose value changed as `filter` describes since the last snapshot,
+    /// then take a new snapshot from `cpu`
+    pub fn narrow(&mut self, cpu: &CPU, filter: ScanFilter) -> &[usize] {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&address| {
+            let old = snapshot.get(address).unwrap_or(0);
+            let new = cpu.memory.get(address).unwrap_or(0);
+            filter.matches(old, new)
+        });
+        self.snapshot = cpu.memory.clone();
+        &self.candidates
+    }
+
+    pub fn candidates(&self) -> &[usize] {
+        &self.candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        // Store the input at 9 and 10, then output it
+        let mut before = CPU::new("3,9,1001,9,0,10,4,10,99,0,0");
+        before.run(Some("7")).expect("Program should halt");
+        let after = before.clone();
+        let mut fresh = CPU::new("3,9,1001,9,0,10,4,10,99,0,0");
+        fresh.set_memory_size(12);
+
+        let diff = fresh.diff(&after);
+        assert_eq!(
+            diff.changes,
+            vec![MemoryChange {
+                start: 9,
+                old: vec![0, 0],
+                new: vec![7, 7],
+            }]
+        );
+        assert_eq!(diff.instruction_pointer_delta, 8);
+        assert_eq!(diff.new_output, vec![7]);
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn test_scan() {
+        // A counter at 20 that goes up by 2 and a flag at 21 that toggles between outputs
+        let program = "1001,20,2,20,1002,21,-1,21,4,20,1105,1,0,0,0,0,0,0,0,0,0,3";
+        let mut cpu = CPU::new(program);
+        cpu.set_exit_on_output();
+        let mut scan = Scan::new(&cpu);
+
+        cpu.run(None).expect("Expected output");
+        scan.narrow(&cpu, ScanFilter::Changed);
+        assert_eq!(scan.candidates(), &[20, 21]);
+
+        cpu.run(None).expect("Expected output");
+        assert_eq!(scan.narrow(&cpu, ScanFilter::IncreasedBy(2)), &[20]);
+    }
+
+    #[test]
+    fn test_scan_extremes() {
+        // Differences that do not fit an isize never match
+        assert!(!ScanFilter::IncreasedBy(1).matches(isize::MIN, isize::MAX));
+        assert!(!ScanFilter::DecreasedBy(1).matches(isize::MAX, isize::MIN));
+        assert!(ScanFilter::IncreasedBy(isize::MAX).matches(0, isize::MAX));
+    }
+}
@@ -0,0 +1,288 @@
+//! State space search over the states of an interactive program, for puzzles like the day 15
+//! repair droid where every input has to be tried from every state.
+//!
+//! Each node owns a CPU stopped at an input request. Expanding it clones the CPU once per
+//! candidate input and runs the clone until it asks for input again. A caller supplied
+//! closure turns the parent key, the input and the outputs of that run into the key of the
+//! new state and the cost of the step, or rejects the step. Keys already visited are not
+//! explored again.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::hash::Hash;
+
+use super::{ExitReason, CPU};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Fewest inputs first
+    BreadthFirst,
+    /// Deepest state first, finds a path without trying to make it short
+    DepthFirst,
+    /// Lowest total cost first, using the step costs returned by the decoder
+    Dijkstra,
+}
+
+/// A goal state and how it was reached
+#[derive(Debug, Clone)]
+pub struct Found<K> {
+    pub key: K,
+    pub inputs: Vec<isize>,
+    /// Sum of the step costs, for every strategy
+    pub cost: usize,
+    pub cpu: CPU,
+}
+
+struct Node<K> {
+    key: K,
+    inputs: Vec<isize>,
+    cost: usize,
+    cpu: CPU,
+    halted: bool,
+}
+
+/// Nodes waiting to be expanded, as indices into the node list
+enum Frontier {
+    Queue(VecDeque<usize>),
+    Stack(Vec<usize>),
+    Heap(BinaryHeap<Reverse<(usize, usize)>>),
+}
+
+impl Frontier {
+    fn new(strategy: Strategy) -> Frontier {
+        match strategy {
+            Strategy::BreadthFirst => Frontier::Queue(VecDeque::new()),
+            Strategy::DepthFirst => Frontier::Stack(vec![]),
+            Strategy::Dijkstra => Frontier::Heap(BinaryHeap::new()),
+        }
+    }
+
+    fn push(&mut self, index: usize, cost: usize) {
+        match self {
+            Frontier::Queue(queue) => queue.push_back(index),
+            Frontier::Stack(stack) => stack.push(index),
+            Frontier::Heap(heap) => heap.push(Reverse((cost, index))),
+        }
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        match self {
+            Frontier::Queue(queue) => queue.pop_front(),
+            Frontier::Stack(stack) => stack.pop(),
+            Frontier::Heap(heap) => heap.pop().map(|Reverse((_, index))| index),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Explore {
+    base: CPU,
+    inputs: Vec<isize>,
+    strategy: Strategy,
+    step_limit: usize,
+}
+
+/// Instructions a single step may execute unless `Explore::step_limit` says otherwise
+pub const DEFAULT_STEP_LIMIT: usize = 1_000_000;
+
+impl Explore {
+    /// Explore from `base` by feeding one of `inputs` at every input request
+    pub fn new(base: &CPU, inputs: &[isize]) -> Explore {
+        let mut base = base.clone();
+        base.clear_exit_on_output();
+        Explore {
+            base,
+            inputs: inputs.to_vec(),
+            strategy: Strategy::BreadthFirst,
+            step_limit: DEFAULT_STEP_LIMIT,
+        }
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Explore {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Drop a step once it executed `limit` instructions without asking for input again
+    pub fn step_limit(mut self, limit: usize) -> Explore {
+        self.step_limit = limit;
+        self
+    }
+
+    /// Search from the state keyed `start` for a state whose key satisfies `goal`.
+    ///
+    /// `decode` gets the key of the state a step starts from, the input fed to it and the
+    /// outputs it produced, and returns the key and cost of the state it ends in, or None
+    /// when the step leads nowhere (a wall, a crash). Steps that fault, including accesses
+    /// outside memory, or that run past the step limit are dropped, and states where the
+    /// program halted are never expanded.
+    pub fn search<K, D, G>(&self, start: K, decode: D, goal: G) -> Option<Found<K>>
+    where
+        K: Clone + Eq + Hash,
+        D: Fn(&K, isize, &[isize]) -> Option<(K, usize)>,
+        G: Fn(&K) -> bool,
+    {
+        let mut nodes: Vec<Option<Node<K>>> = vec![];
+        let mut frontier = Frontier::new(self.strategy);
+        let mut visited: HashSet<K> = HashSet::new();
+        // Dijkstra may find a cheaper way to a key after first seeing it, so it only
+        // settles keys as they come off the frontier
+        let settle_on_pop = self.strategy == Strategy::Dijkstra;
+
+        let mut base = self.base.clone();
+        base.set_step_limit(base.get_steps() + self.step_limit);
+        let halted = match base.run(None) {
+            Ok(ExitReason::InputRequired) => false,
+            Ok(_) => true,
+            Err(_) => return None,
+        };
+        if !settle_on_pop {
+            visited.insert(start.clone());
+        }
+        nodes.push(Some(Node {
+            key: start,
+            inputs: vec![],
+            cost: 0,
+            cpu: base,
+            halted,
+        }));
+        frontier.push(0, 0);
+
+        while let Some(index) = frontier.pop() {
+            let node = nodes[index].take().expect("Node expanded twice");
+            if settle_on_pop && !visited.insert(node.key.clone()) {
+                continue;
+            }
+            if goal(&node.key) {
+                return Some(Found {
+                    key: node.key,
+                    inputs: node.inputs,
+                    cost: node.cost,
+                    cpu: node.cpu,
+                });
+            }
+            if node.halted {
+                continue;
+            }
+
+            for input in &self.inputs {
+                let mut cpu = node.cpu.clone();
+                let produced = cpu.get_output().len();
+                cpu.set_step_limit(cpu.get_steps() + self.step_limit);
+                let halted = match cpu.run(Some(&input.to_string())) {
+                    Ok(ExitReason::InputRequired) => false,
+                    Ok(_) => true,
+                    Err(_) => continue,
+                };
+                let output = cpu.get_output();
+                let (key, step_cost) = match decode(&node.key, *input, &output[produced..]) {
+                    Some(step) => step,
+                    None => continue,
+                };
+                if settle_on_pop {
+                    if visited.contains(&key) {
+                        continue;
+                    }
+                } else if !visited.insert(key.clone()) {
+                    continue;
+                }
+
+                let mut inputs = node.inputs.clone();
+                inputs.push(*input);
+                let cost = node.cost + step_cost;
+                nodes.push(Some(Node {
+                    key,
+                    inputs,
+                    cost,
+                    cpu,
+                    halted,
+                }));
+                frontier.push(nodes.len() - 1, cost);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NORTH: isize = 1;
+    const SOUTH: isize = 2;
+    const WEST: isize = 3;
+    const EAST: isize = 4;
+
+    /// Droid position and whether it is standing on the oxygen system
+    type Droid = ((isize, isize), bool);
+
+    fn droid_step(droid: &Droid, input: isize, output: &[isize]) -> Option<(Droid, usize)> {
+        let ((x, y), _) = *droid;
+        let next = match input {
+            NORTH => (x, y - 1),
+            SOUTH => (x, y + 1),
+            WEST => (x - 1, y),
+            EAST => (x + 1, y),
+            _ => unreachable!(),
+        };
+        match output {
+            [0] => None,
+            [1] => Some(((next, false), 1)),
+            [2] => Some(((next, true), 1)),
+            _ => panic!("Unexpected droid output {:?}", output),
+        }
+    }
+
+    fn find_oxygen(strategy: Strategy) -> Found<Droid> {
+        let mut cpu = CPU::new(include_str!("../../input/2019/day15.txt"));
+        cpu.set_memory_size(4096);
+        Explore::new(&cpu, &[NORTH, SOUTH, WEST, EAST])
+            .strategy(strategy)
+            .search(((0, 0), false), droid_step, |(_, oxygen)| *oxygen)
+            .expect("The oxygen system is reachable")
+    }
+
+    #[test]
+    fn test_repair_droid() {
+        let shortest = find_oxygen(Strategy::BreadthFirst);
+        assert_eq!(shortest.cost, shortest.inputs.len());
+        assert_eq!(shortest.cpu.get_last_output(), Some(&2));
+        assert_eq!(find_oxygen(Strategy::Dijkstra).cost, shortest.cost);
+
+        let any = find_oxygen(Strategy::DepthFirst);
+        assert_eq!(any.key, shortest.key);
+        assert!(any.cost >= shortest.cost);
+    }
+
+    #[test]
+    fn test_weighted_costs() {
+        // Echo the input back, and stop once it has seen 3
+        let cpu = CPU::new("3,13,4,13,1008,13,3,14,1006,14,0,99,0,0,0");
+        let found = Explore::new(&cpu, &[1, 2, 3])
+            .strategy(Strategy::Dijkstra)
+            .search(
+                0,
+                |total, _, output| Some((total + output[0], output[0] as usize)),
+                |total| *total >= 4,
+            )
+            .expect("Reachable total");
+        assert_eq!(found.key, 4);
+        assert_eq!(found.cost, 4);
+        assert_eq!(found.inputs.iter().sum::<isize>(), 4);
+        assert!(found.inputs.len() >= 2);
+    }
+
+    #[test]
+    fn test_failing_steps() {
+        // Input 1 loops forever, 2 outputs from outside memory and 3 is echoed back
+        let cpu = CPU::new(
+            "3,30,1008,30,1,31,1005,31,6,1008,30,2,31,1006,31,18,4,500,4,30,1105,1,0,\
+             0,0,0,0,0,0,0,0,0",
+        );
+        let found = Explore::new(&cpu, &[1, 2, 3])
+            .step_limit(1000)
+            .search(0, |depth, _, _| Some((depth + 1, 1)), |depth| *depth == 2)
+            .expect("Echoing is never dropped");
+        assert_eq!(found.inputs, vec![3, 3]);
+    }
+}
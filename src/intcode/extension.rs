@@ -0,0 +1,275 @@
+//! Host defined opcodes. A registry maps otherwise unused opcodes to an operand layout and a
+//! handler with mutable access to the CPU, for instrumentation, host calls and variants of
+//! the instruction set. The built in opcodes cannot be replaced, and opcodes nobody
+//! registered still fail with `CpuError::InvalidOpcode`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::decode::{self, Op, ParameterMode};
+use super::memory::Memory;
+use super::{CpuError, CpuResult, CPU};
+
+/// How the handler wants to see an operand, following the parameter mode in both cases
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    /// The value the operand refers to
+    Read,
+    /// The address the operand refers to, for the handler to write through
+    Write,
+}
+
+/// Where execution goes after a handler returns
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    /// The instruction following this one
+    Next,
+    Jump(usize),
+    Halt,
+}
+
+pub type Handler = dyn Fn(&mut CPU, &[isize]) -> CpuResult<Flow> + Send + Sync;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    /// Opcodes are the last two digits of a word, so only 1 to 98 can be added
+    OutOfRange(isize),
+    BuiltIn(isize),
+    AlreadyRegistered(isize),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::OutOfRange(opcode) => write!(f, "Opcode {} is not in 1..=98", opcode),
+            RegistryError::BuiltIn(opcode) => write!(f, "Opcode {} is built in", opcode),
+            RegistryError::AlreadyRegistered(opcode) => {
+                write!(f, "Opcode {} is already registered", opcode)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Extension {
+    pub name: String,
+    pub operands: Vec<OperandKind>,
+    handler: Arc<Handler>,
+}
+
+impl Extension {
+    /// Decode the instruction at `address` with the operands this extension takes
+    pub(crate) fn decode(&self, memory: &Memory, address: usize) -> Option<Op> {
+        decode::decode_operands(|address| memory.get(address), address, self.operands.len())
+    }
+}
+
+impl fmt::Debug for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extension")
+            .field("name", &self.name)
+            .field("operands", &self.operands)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OpcodeRegistry {
+    extensions: BTreeMap<isize, Extension>,
+}
+
+impl OpcodeRegistry {
+    pub fn new() -> OpcodeRegistry {
+        OpcodeRegistry::default()
+    }
+
+    /// Add `opcode`, taking `operands` and running `handler` with their resolved values
+    pub fn register<F>(
+        &mut self,
+        opcode: isize,
+        name: &str,
+        operands: &[OperandKind],
+        handler: F,
+    ) -> Result<(), RegistryError>
+    where
+        F: Fn(&mut CPU, &[isize]) -> CpuResult<Flow> + Send + Sync + 'static,
+    {
+        if decode::arity(opcode).is_some() {
+            return Err(RegistryError::BuiltIn(opcode));
+        }
+        if !(1..=98).contains(&opcode) {
+            return Err(RegistryError::OutOfRange(opcode));
+        }
+        if self.extensions.contains_key(&opcode) {
+            return Err(RegistryError::AlreadyRegistered(opcode));
+        }
+        self.extensions.insert(
+            opcode,
+            Extension {
+                name: name.to_string(),
+                operands: operands.to_vec(),
+                handler: Arc::new(handler),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&self, opcode: isize) -> Option<&Extension> {
+        self.extensions.get(&opcode)
+    }
+}
+
+impl CPU {
+    /// Run the opcodes in `registry` as if they were part of the instruction set
+    pub fn set_extensions(&mut self, registry: OpcodeRegistry) {
+        self.extensions = Some(Arc::new(registry));
+    }
+
+    /// The registered extension for the instruction at the instruction pointer, if any
+    pub(crate) fn pending_extension(&self) -> Option<(Arc<OpcodeRegistry>, isize)> {
+        let registry = self.extensions.as_ref()?;
+        let word = self.memory.get(self.instruction_pointer)?;
+        let opcode = word % 100;
+        if word >= 0 && registry.get(opcode).is_some() {
+            Some((registry.clone(), opcode))
+        } else {
+            None
+        }
+    }
+
+    /// Resolve the operands of the extension at the instruction pointer and run its handler
+    pub(crate) fn execute_extension(&mut self, extension: &Extension) -> CpuResult<Flow> {
+        let ip = self.instruction_pointer;
+        let op = extension
+            .decode(&self.memory, ip)
+            .ok_or_else(|| CpuError::InvalidOpcode(self.memory[ip], ip))?;
+        let arguments = op
+            .operands
+            .iter()
+            .zip(&extension.operands)
+            .map(|(operand, kind)| {
+                let address = match operand.mode {
+                    ParameterMode::Relative => self.relative_base + operand.raw,
+                    _ => operand.raw,
+                };
+                match (kind, operand.mode) {
+                    (OperandKind::Read, ParameterMode::Immediate) => Ok(operand.raw),
+                    (OperandKind::Read, _) => Ok(self.get_memory(self.address(address, ip)?)),
+                    (OperandKind::Write, _) => Ok(address),
+                }
+            })
+            .collect::<CpuResult<Vec<isize>>>()?;
+
+        let flow = (extension.handler)(self, &arguments)?;
+        if flow == Flow::Next {
+            self.instruction_pointer = op.next();
+        }
+        Ok(flow)
+    }
+
+    /// Store a value the way the program itself does, for handlers writing through their
+    /// operands. Unlike `set_memory` this is not host input, so loop detection and strict
+    /// mode treat it like any other instruction's write. Fails outside memory.
+    pub fn store(&mut self, address: isize, value: isize) -> CpuResult<()> {
+        let cell = self.address(address, self.instruction_pointer)?;
+        self.write(cell, value);
+        self.taint_extension_write(cell);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ExitReason;
+    use super::*;
+    use std::sync::Mutex;
+
+    fn registry() -> OpcodeRegistry {
+        let mut registry = OpcodeRegistry::new();
+        registry
+            .register(
+                42,
+                "max",
+                &[OperandKind::Read, OperandKind::Read, OperandKind::Write],
+                |cpu, arguments| {
+                    cpu.store(arguments[2], arguments[0].max(arguments[1]))?;
+                    Ok(Flow::Next)
+                },
+            )
+            .unwrap();
+        registry
+            .register(50, "stop", &[], |_, _| Ok(Flow::Halt))
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_extension_opcodes() {
+        // max([9], 7) into [rb+10], then output it and stop
+        let mut cpu = CPU::new("109,2,21042,9,7,10,4,12,50,-5,0,0,0");
+        cpu.set_extensions(registry());
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(cpu.get_output(), vec![7]);
+        assert_eq!(cpu.get_memory(12), 7);
+    }
+
+    #[test]
+    fn test_host_call() {
+        let printed = Arc::new(Mutex::new(vec![]));
+        let sink = printed.clone();
+        let mut registry = OpcodeRegistry::new();
+        registry
+            .register(
+                10,
+                "print_range",
+                &[OperandKind::Read, OperandKind::Read],
+                move |cpu, arguments| {
+                    let range = arguments[0] as usize..arguments[1] as usize;
+                    let values: Vec<isize> = range.map(|address| cpu.get_memory(address)).collect();
+                    sink.lock().unwrap().push(values);
+                    Ok(Flow::Jump(4))
+                },
+            )
+            .unwrap();
+        let mut cpu = CPU::new("1110,5,8,0,99,1,2,3");
+        cpu.set_extensions(registry);
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(*printed.lock().unwrap(), vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn test_registration_rules() {
+        let mut registry = registry();
+        let noop = |_: &mut CPU, _: &[isize]| Ok(Flow::Next);
+        assert_eq!(
+            registry.register(1, "add", &[], noop),
+            Err(RegistryError::BuiltIn(1))
+        );
+        assert_eq!(
+            registry.register(99, "halt", &[], noop),
+            Err(RegistryError::BuiltIn(99))
+        );
+        assert_eq!(
+            registry.register(100, "big", &[], noop),
+            Err(RegistryError::OutOfRange(100))
+        );
+        assert_eq!(
+            registry.register(42, "again", &[], noop),
+            Err(RegistryError::AlreadyRegistered(42))
+        );
+
+        let mut cpu = CPU::new("43,99");
+        cpu.set_extensions(registry);
+        assert_eq!(cpu.run(None), Err(CpuError::InvalidOpcode(43, 0)));
+    }
+
+    #[test]
+    fn test_store_is_not_input() {
+        // Storing the same maximum on every lap is still a loop
+        let mut cpu = CPU::new("1142,7,7,7,1105,1,0,0");
+        cpu.set_loop_detection(1);
+        cpu.set_extensions(registry());
+        assert_eq!(cpu.run(None), Err(CpuError::LoopDetected(4)));
+    }
+}
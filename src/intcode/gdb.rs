@@ -0,0 +1,515 @@
+//! A GDB remote serial protocol stub, so a debugger can attach to a CPU over TCP.
+//!
+//! The target has two 64 bit registers, `ip` (register 0, the program counter) and `rb`
+//! (register 1, the relative base). Memory is presented as bytes, each intcode cell taking
+//! eight of them little endian, so cell `n` is at byte address `8 * n` and `x/4gd 0` in gdb
+//! shows the first four cells. Breakpoints (`Z0` and `Z1`) are set on cells the same way.
+//!
+//! The program reads its input from a queue filled with `monitor input 1,2,3`, and each
+//! output is printed on the debugger's console as it happens. When the queue runs dry the
+//! program stops with `SIGTRAP`, as if it hit a breakpoint on the input instruction.
+//! `monitor backtrace` prints the shadow call stack of `intcode::calls`. A fault stops the
+//! program with `SIGILL` for instructions that cannot run, `SIGSEGV` for memory errors and
+//! `SIGABRT` otherwise, and a halt ends it with exit code 0.
+//!
+//! Only one debugger is served at a time, and packets the debugger rejects are not sent
+//! again.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use super::{CpuError, ExitReason, CPU};
+
+const PACKET_SIZE: usize = 0x1000;
+
+/// How many instructions run between checks for an interrupt from the debugger
+const INTERRUPT_INTERVAL: usize = 1024;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.intcode.cpu">
+    <reg name="ip" bitsize="64" type="code_ptr" regnum="0"/>
+    <reg name="rb" bitsize="64" type="int64" regnum="1"/>
+  </feature>
+</target>
+"#;
+
+/// Why the program stopped running
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    Signal(u8),
+    /// Input was needed and the queue was empty
+    Starved,
+    Fault(CpuError),
+    Exited,
+}
+
+impl Stop {
+    fn reply(self) -> String {
+        match self {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Starved => format!("S{:02x}", SIGTRAP),
+            Stop::Fault(error) => format!("S{:02x}", signal(error)),
+            Stop::Exited => "W00".to_string(),
+        }
+    }
+}
+
+fn signal(error: CpuError) -> u8 {
+    match error {
+        CpuError::InvalidOpcode(..)
+        | CpuError::OpcodeNotInProfile(..)
+        | CpuError::ModeNotInProfile(..) => SIGILL,
+        CpuError::UninitializedRead(..)
+        | CpuError::ReadViolation(..)
+        | CpuError::WriteViolation(..)
+        | CpuError::ExecuteViolation(..)
+        | CpuError::AddressOutOfBounds(..) => SIGSEGV,
+        _ => SIGABRT,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Bytes from hex digits, `None` for odd lengths or anything but hex digits
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+/// A register value as gdb sends it, little endian hex
+fn register(text: &str) -> Option<isize> {
+    let bytes = unhex(text)?;
+    let mut word = [0; 8];
+    if bytes.len() != 8 {
+        return None;
+    }
+    word.copy_from_slice(&bytes);
+    Some(i64::from_le_bytes(word) as isize)
+}
+
+fn number(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// The `address,length` of memory packets
+fn range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    Some((number(parts.next()?)?, number(parts.next()?)?))
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+/// Packet framing over one TCP connection
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    acknowledge: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        // Packets are small and each one waits for the answer to the last
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            acknowledge: true,
+        })
+    }
+
+    /// The next packet, or `None` once the debugger hangs up. Acknowledgements and stray
+    /// interrupts between packets are skipped.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut start = [0];
+            if self.reader.read(&mut start)? == 0 {
+                return Ok(None);
+            }
+            if start[0] != b'$' {
+                continue;
+            }
+            let mut data = vec![];
+            if self.reader.read_until(b'#', &mut data)? == 0 || data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&data));
+            if self.acknowledge {
+                self.writer.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.writer, "${}#{:02x}", data, checksum(data))?;
+        self.writer.flush()
+    }
+
+    /// Text for the debugger's console
+    fn console(&mut self, text: &str) -> io::Result<()> {
+        self.send(&format!("O{}", hex(text.as_bytes())))
+    }
+
+    /// Whether the debugger sent an interrupt, without waiting for one
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.reader.buffer().is_empty() {
+            self.reader.get_ref().set_nonblocking(true)?;
+            let filled = self.reader.fill_buf().map(|buffer| buffer.len());
+            self.reader.get_ref().set_nonblocking(false)?;
+            match filled {
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(error) => return Err(error),
+            }
+        }
+        match self.reader.buffer().iter().position(|byte| *byte == 0x03) {
+            Some(position) => {
+                self.reader.consume(position + 1);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Serves one CPU to a debugger
+#[derive(Debug)]
+pub struct GdbStub {
+    cpu: CPU,
+    breakpoints: BTreeSet<usize>,
+    input: VecDeque<isize>,
+    /// Outputs already shown on the console
+    shown: usize,
+    stop: Stop,
+}
+
+impl GdbStub {
+    pub fn new(mut cpu: CPU) -> GdbStub {
+        if cpu.backtrace().is_none() {
+            cpu.set_call_tracking();
+        }
+        let shown = cpu.output.len();
+        GdbStub {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            input: VecDeque::new(),
+            shown,
+            stop: Stop::Signal(SIGTRAP),
+        }
+    }
+
+    /// Queue values for the program to read
+    pub fn push_input(&mut self, values: &[isize]) {
+        self.input.extend(values);
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU {
+        self.cpu
+    }
+
+    /// Talk to the debugger on `stream` until it detaches, kills the program or hangs up
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut connection = Connection::new(stream)?;
+        while let Some(packet) = connection.receive()? {
+            match self.handle(&packet, &mut connection)? {
+                Some(reply) => connection.send(&reply)?,
+                None => return Ok(()),
+            }
+            match packet.as_str() {
+                "D" => return Ok(()),
+                "QStartNoAckMode" => connection.acknowledge = false,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The reply to `packet`, or `None` to end the session
+    fn handle(&mut self, packet: &str, connection: &mut Connection) -> io::Result<Option<String>> {
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.stop.reply(),
+            "g" => hex(&self.registers()),
+            "G" => self.set_registers(arguments),
+            "p" => match number(arguments) {
+                Some(index) if index < 2 => hex(&self.registers()[index * 8..index * 8 + 8]),
+                _ => "E01".to_string(),
+            },
+            "P" => self.set_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "Z" | "z" => self.breakpoint(command == "Z", arguments),
+            "s" | "c" => {
+                self.stop = self.resume(command == "s", connection)?;
+                self.stop.reply()
+            }
+            "H" | "T" => "OK".to_string(),
+            "D" => "OK".to_string(),
+            "k" => return Ok(None),
+            "q" | "Q" | "v" => self.query(packet, connection)?,
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str, connection: &mut Connection) -> io::Result<String> {
+        let reply = if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_string()
+        } else if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match range(request) {
+                Some((offset, length)) => {
+                    let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+                    if rest.len() > length {
+                        format!("m{}", &rest[..length])
+                    } else {
+                        format!("l{}", rest)
+                    }
+                }
+                None => "E01".to_string(),
+            }
+        } else if let Some(command) = packet.strip_prefix("qRcmd,") {
+            match unhex(command).map(|bytes| String::from_utf8_lossy(&bytes).into_owned()) {
+                Some(command) => self.monitor(command.trim(), connection)?,
+                None => "E01".to_string(),
+            }
+        } else {
+            match packet {
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                "qSymbol::" => "OK".to_string(),
+                _ => String::new(),
+            }
+        };
+        Ok(reply)
+    }
+
+    /// The `monitor` commands
+    fn monitor(&mut self, command: &str, connection: &mut Connection) -> io::Result<String> {
+        let (name, rest) = match command.find(' ') {
+            Some(space) => (&command[..space], command[space + 1..].trim()),
+            None => (command, ""),
+        };
+        match name {
+            "input" => {
+                let values: Result<Vec<isize>, _> =
+                    rest.split(',').map(|value| value.trim().parse()).collect();
+                match values {
+                    Ok(values) => {
+                        self.push_input(&values);
+                        Ok("OK".to_string())
+                    }
+                    Err(_) => {
+                        connection.console(&format!("Invalid input `{}`\n", rest))?;
+                        Ok("E01".to_string())
+                    }
+                }
+            }
+            "backtrace" => {
+                if let Some(backtrace) = self.cpu.backtrace() {
+                    connection.console(&format!("{}\n", backtrace))?;
+                }
+                Ok("OK".to_string())
+            }
+            _ => {
+                connection.console(&format!("Unknown monitor command `{}`\n", name))?;
+                Ok("E01".to_string())
+            }
+        }
+    }
+
+    fn registers(&self) -> Vec<u8> {
+        let mut bytes = (self.cpu.instruction_pointer as i64).to_le_bytes().to_vec();
+        bytes.extend(&(self.cpu.relative_base as i64).to_le_bytes());
+        bytes
+    }
+
+    fn set_registers(&mut self, text: &str) -> String {
+        match (
+            text.get(..16).and_then(register),
+            text.get(16..).and_then(register),
+        ) {
+            (Some(ip), Some(rb)) if ip >= 0 => {
+                self.cpu.instruction_pointer = ip as usize;
+                self.cpu.relative_base = rb;
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn set_register(&mut self, text: &str) -> String {
+        let mut parts = text.splitn(2, '=');
+        let index = parts.next().and_then(number);
+        match (index, parts.next().and_then(register)) {
+            (Some(0), Some(ip)) if ip >= 0 => self.cpu.instruction_pointer = ip as usize,
+            (Some(1), Some(rb)) => self.cpu.relative_base = rb,
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, text: &str) -> String {
+        let (address, length) = match range(text) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let end = match address.checked_add(length.min(PACKET_SIZE / 2)) {
+            Some(end) => end,
+            None => return "E14".to_string(),
+        };
+        let bytes: Vec<u8> = (address..end)
+            .map_while(|byte| {
+                let cell = self.cpu.memory.get(byte / 8)?;
+                Some((cell as i64).to_le_bytes()[byte % 8])
+            })
+            .collect();
+        if bytes.is_empty() && length > 0 {
+            "E14".to_string()
+        } else {
+            hex(&bytes)
+        }
+    }
+
+    fn write_memory(&mut self, text: &str) -> String {
+        let mut parts = text.splitn(2, ':');
+        let range = parts.next().and_then(range);
+        let bytes = parts.next().and_then(unhex);
+        let (address, bytes) = match (range, bytes) {
+            (Some((address, length)), Some(bytes)) if bytes.len() == length => (address, bytes),
+            _ => return "E01".to_string(),
+        };
+        match address.checked_add(bytes.len()) {
+            Some(end) if end <= self.cpu.memory.len() * 8 => {}
+            _ => return "E14".to_string(),
+        }
+        for (offset, byte) in bytes.iter().enumerate() {
+            let cell = (address + offset) / 8;
+            let mut word = (self.cpu.memory[cell] as i64).to_le_bytes();
+            word[(address + offset) % 8] = *byte;
+            self.cpu.set_memory(cell, i64::from_le_bytes(word) as isize);
+        }
+        "OK".to_string()
+    }
+
+    fn breakpoint(&mut self, insert: bool, text: &str) -> String {
+        let mut parts = text.split(',');
+        let kind = parts.next();
+        let address = parts.next().and_then(number);
+        match (kind, address) {
+            (Some("0"), Some(address)) | (Some("1"), Some(address)) => {
+                if insert {
+                    self.breakpoints.insert(address / 8);
+                } else {
+                    self.breakpoints.remove(&(address / 8));
+                }
+                "OK".to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Run one instruction, or until a breakpoint or something else stops the program
+    fn resume(&mut self, single: bool, connection: &mut Connection) -> io::Result<Stop> {
+        let mut steps = 0;
+        loop {
+            let stopped = self.step();
+            self.show_outputs(connection)?;
+            match stopped {
+                Some(Stop::Starved) => {
+                    connection.console("The program is waiting for `monitor input`\n")?;
+                    return Ok(Stop::Starved);
+                }
+                Some(Stop::Fault(error)) => {
+                    connection.console(&format!("CPU fault : {:?}\n", error))?;
+                    self.monitor("backtrace", connection)?;
+                    return Ok(Stop::Fault(error));
+                }
+                Some(stop) => return Ok(stop),
+                None => {}
+            }
+            if single || self.breakpoints.contains(&self.cpu.instruction_pointer) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+            steps += 1;
+            if steps % INTERRUPT_INTERVAL == 0 && connection.interrupted()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    /// Run the instruction at the instruction pointer, handing it the next queued input if
+    /// it reads one. Returns why the program stopped if it did.
+    fn step(&mut self) -> Option<Stop> {
+        let cpu = &mut self.cpu;
+        let reads = cpu
+            .memory
+            .get(cpu.instruction_pointer)
+            .is_some_and(|word| word % 100 == 3);
+        let input = match self.input.front() {
+            Some(value) if reads => Some(value.to_string()),
+            _ => None,
+        };
+        let limit = cpu.step_limit;
+        if limit.is_some_and(|limit| cpu.steps >= limit) {
+            return Some(Stop::Fault(CpuError::StepLimitReached(
+                cpu.instruction_pointer,
+            )));
+        }
+        cpu.step_limit = Some(cpu.steps + 1);
+        let result = cpu.run(input.as_deref());
+        cpu.step_limit = limit;
+        match result {
+            Err(CpuError::StepLimitReached(_)) | Ok(ExitReason::OutputGenerated) => {
+                if input.is_some() {
+                    self.input.pop_front();
+                }
+                None
+            }
+            Ok(ExitReason::InputRequired) => Some(Stop::Starved),
+            Ok(ExitReason::Halt) => Some(Stop::Exited),
+            Err(error) => Some(Stop::Fault(error)),
+        }
+    }
+
+    fn show_outputs(&mut self, connection: &mut Connection) -> io::Result<()> {
+        while self.shown < self.cpu.output.len() {
+            connection.console(&format!("{}\n", self.cpu.output[self.shown]))?;
+            self.shown += 1;
+        }
+        Ok(())
+    }
+}
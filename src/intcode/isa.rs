@@ -0,0 +1,133 @@
+//! Instruction set profiles. The puzzles grow the instruction set in stages, and a CPU
+//! built with an earlier profile rejects what came later instead of running it, which keeps
+//! old solutions honest about the machine they were written for.
+
+use super::decode::{self, ParameterMode};
+use super::{CpuError, CpuResult, CPU};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IsaProfile {
+    /// Add, multiply and halt, position mode only
+    Day2,
+    /// Input, output, jumps and compares, with immediate mode
+    Day5,
+    /// Relative mode and relative base adjustment, the whole instruction set
+    #[default]
+    Day9,
+}
+
+impl IsaProfile {
+    pub fn allows_opcode(self, opcode: isize) -> bool {
+        match opcode {
+            1 | 2 | 99 => true,
+            3..=8 => self >= IsaProfile::Day5,
+            9 => self >= IsaProfile::Day9,
+            _ => false,
+        }
+    }
+
+    pub fn allows_mode(self, mode: ParameterMode) -> bool {
+        match mode {
+            ParameterMode::Position => true,
+            ParameterMode::Immediate => self >= IsaProfile::Day5,
+            ParameterMode::Relative => self >= IsaProfile::Day9,
+        }
+    }
+}
+
+impl CPU {
+    /// Build a CPU that only runs the instructions of `profile`
+    pub fn with_profile(program: &str, profile: IsaProfile) -> CPU {
+        let mut cpu = CPU::new(program);
+        cpu.profile = profile;
+        cpu
+    }
+
+    pub fn profile(&self) -> IsaProfile {
+        self.profile
+    }
+
+    /// Fail if the instruction at the instruction pointer is outside the profile. Words
+    /// that are not instructions at all are left for the decoder to reject.
+    pub(crate) fn check_profile(&self) -> CpuResult<()> {
+        if self.profile == IsaProfile::Day9 {
+            return Ok(());
+        }
+        let ip = self.instruction_pointer;
+        let word = self.memory[ip];
+        let arity = match self.pending_extension() {
+            // Registered opcodes belong to every profile, the modes they use do not
+            Some((registry, opcode)) => registry
+                .get(opcode)
+                .map_or(0, |extension| extension.operands.len()),
+            None => {
+                let arity = match decode::arity(word % 100) {
+                    Some(arity) => arity,
+                    None => return Ok(()),
+                };
+                if !self.profile.allows_opcode(word % 100) {
+                    return Err(CpuError::OpcodeNotInProfile(word, ip));
+                }
+                arity
+            }
+        };
+        let mut flags = word / 100;
+        for _ in 0..arity {
+            if let Some(mode) = ParameterMode::from_digit(flags % 10) {
+                if !self.profile.allows_mode(mode) {
+                    return Err(CpuError::ModeNotInProfile(word, ip));
+                }
+            }
+            flags /= 10;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ExitReason;
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let io = "3,0,4,0,99";
+        let mut cpu = CPU::with_profile(io, IsaProfile::Day2);
+        assert_eq!(cpu.run(Some("5")), Err(CpuError::OpcodeNotInProfile(3, 0)));
+        let mut cpu = CPU::with_profile(io, IsaProfile::Day5);
+        assert_eq!(cpu.run(Some("5")), Ok(ExitReason::Halt));
+        assert_eq!(cpu.get_output(), vec![5]);
+
+        let mut cpu = CPU::with_profile("1,0,0,0,109,1,99", IsaProfile::Day5);
+        assert_eq!(cpu.run(None), Err(CpuError::OpcodeNotInProfile(109, 4)));
+        assert_eq!(cpu.get_memory(0), 2);
+    }
+
+    #[test]
+    fn test_extension_modes() {
+        use super::super::extension::{Flow, OpcodeRegistry, OperandKind};
+
+        // A host `out` on [rb+20], which the Day5 profile rejects for its relative mode
+        let mut registry = OpcodeRegistry::new();
+        registry
+            .register(42, "out", &[OperandKind::Read], |cpu, arguments| {
+                cpu.output.push(arguments[0]);
+                Ok(Flow::Next)
+            })
+            .unwrap();
+        let mut cpu = CPU::with_profile("242,20,99", IsaProfile::Day5);
+        cpu.set_extensions(registry);
+        assert_eq!(cpu.run(None), Err(CpuError::ModeNotInProfile(242, 0)));
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut cpu = CPU::with_profile("1101,2,3,0,99", IsaProfile::Day2);
+        assert_eq!(cpu.run(None), Err(CpuError::ModeNotInProfile(1101, 0)));
+        let mut cpu = CPU::with_profile("1201,0,3,0,99", IsaProfile::Day5);
+        assert_eq!(cpu.run(None), Err(CpuError::ModeNotInProfile(1201, 0)));
+        let mut cpu = CPU::with_profile("1201,0,3,0,99", IsaProfile::Day9);
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(cpu.get_memory(0), 1204);
+    }
+}
@@ -0,0 +1,393 @@
+//! Static checks for intcode programs.
+//!
+//! The reachable code is found by walking every path from address 0 and following
+//! constant jump targets, as the decompiler does. Jumps through memory cannot be followed,
+//! so when a program has any, the instruction after each unconditional jump is assumed
+//! reachable too, since that is where calls return. Those guesses can land on data, so
+//! nothing found only through them is reported as an error. Along the way the walk tracks
+//! whether an `arb` has run on every path to an instruction.
+//!
+//! Cells of the program that no instruction stores to through position mode are taken to
+//! keep their initial values, so a jump testing one of them goes one way only. Relative
+//! mode stores are assumed to stay on the stack past the program. Dead code is not
+//! reported for programs that rewrite their own code.
+//!
+//! Diagnostics print like a compiler's, `day9.txt:63: warning: ... [relative_before_arb]`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use super::decode::{decode, Op, Operand, ParameterMode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lint {
+    /// An instruction storing its result through an immediate operand
+    ImmediateWrite,
+    /// A reachable word that is not an instruction, unless something writes one there
+    InvalidOpcode(isize),
+    /// A constant jump target outside the program
+    JumpOutOfBounds(isize),
+    /// Execution runs past the last word of the program
+    FallsOffEnd,
+    /// Relative mode on a path where no `arb` has set the relative base yet
+    RelativeBeforeArb,
+    /// Instructions up to this address that no path reaches
+    DeadCode(usize),
+}
+
+impl Lint {
+    /// Short name, as shown in brackets after the message
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::ImmediateWrite => "immediate_write",
+            Lint::InvalidOpcode(_) => "invalid_opcode",
+            Lint::JumpOutOfBounds(_) => "jump_out_of_bounds",
+            Lint::FallsOffEnd => "falls_off_end",
+            Lint::RelativeBeforeArb => "relative_before_arb",
+            Lint::DeadCode(_) => "dead_code",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Lint::InvalidOpcode(_) | Lint::FallsOffEnd => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    pub address: usize,
+    pub lint: Lint,
+    /// Listing of the instruction at `address`, when it decodes
+    pub instruction: Option<String>,
+}
+
+impl Diagnostic {
+    /// A compiler style line naming `source`, the file the program came from
+    pub fn render(&self, source: &str) -> String {
+        format!("{}:{}", source, self)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: ", self.address, self.lint.severity())?;
+        match &self.lint {
+            Lint::ImmediateWrite => write!(f, "result stored through an immediate operand")?,
+            Lint::InvalidOpcode(word) => {
+                write!(f, "reachable word {} is not an instruction", word)?
+            }
+            Lint::JumpOutOfBounds(target) => write!(f, "jump to {}, outside the program", target)?,
+            Lint::FallsOffEnd => write!(f, "execution runs past the end of the program")?,
+            Lint::RelativeBeforeArb => {
+                write!(f, "relative mode may be used before any arb sets the base")?
+            }
+            Lint::DeadCode(end) => write!(f, "unreachable code up to {}", end - 1)?,
+        }
+        if let Some(instruction) = &self.instruction {
+            write!(f, " in `{}`", instruction)?;
+        }
+        write!(f, " [{}]", self.lint.name())
+    }
+}
+
+/// Check `program`, returning the diagnostics sorted by address
+pub fn lint(program: &[isize]) -> Vec<Diagnostic> {
+    // A generous first walk finds every cell the program might store to. The rest keep
+    // their initial values, which settles conditions that only look at them.
+    let fixed = |code: &BTreeMap<usize, Op>| {
+        let written = writes(code);
+        move |cell: usize| {
+            program
+                .get(cell)
+                .copied()
+                .filter(|_| !written.contains(&cell))
+        }
+    };
+    let first = walk(program, true, &|_| None);
+    let reached = walk(program, first.indirect, &fixed(&first.code));
+
+    // Words reachable code stores to may hold an instruction by the time they run
+    let written = writes(&reached.code);
+    let (mut diagnostics, rewritten): (Vec<Diagnostic>, Vec<Diagnostic>) =
+        reached.diagnostics.into_iter().partition(|diagnostic| {
+            !matches!(diagnostic.lint, Lint::InvalidOpcode(_))
+                || !written.contains(&diagnostic.address)
+        });
+
+    let reachable: HashSet<usize> = reached
+        .code
+        .values()
+        .flat_map(|op| op.address..op.next())
+        .collect();
+    // Code that rewrites itself can go anywhere
+    if rewritten.is_empty() && reachable.is_disjoint(&written) {
+        let data: HashSet<usize> = reached
+            .code
+            .values()
+            .flat_map(|op| op.operands.iter())
+            .filter(|operand| operand.mode == ParameterMode::Position && operand.raw >= 0)
+            .map(|operand| operand.raw as usize)
+            .collect();
+        let mut address = 0;
+        while address < program.len() {
+            match dead_block(program, address, &reachable, &data) {
+                // A single instruction is as likely to be numbers in a table, and a block
+                // starting at an address some word holds may be reached through memory
+                Some(end)
+                    if decode(program, address).map(|op| op.next()) == Some(end)
+                        || program.contains(&(address as isize)) =>
+                {
+                    address = end
+                }
+                Some(end) => {
+                    diagnostics.push(diagnostic(program, address, Lint::DeadCode(end)));
+                    address = end;
+                }
+                None => address += 1,
+            }
+        }
+    }
+
+    diagnostics.sort();
+    diagnostics.dedup();
+    diagnostics
+}
+
+/// Cells stored to through position mode
+fn writes(code: &BTreeMap<usize, Op>) -> HashSet<usize> {
+    code.values()
+        .filter_map(|op| op.write_operand().map(|index| op.operands[index]))
+        .filter(|operand| operand.mode == ParameterMode::Position && operand.raw >= 0)
+        .map(|operand| operand.raw as usize)
+        .collect()
+}
+
+fn diagnostic(program: &[isize], address: usize, lint: Lint) -> Diagnostic {
+    Diagnostic {
+        address,
+        lint,
+        instruction: decode(program, address).map(|op| op.to_string()),
+    }
+}
+
+struct Walk {
+    code: BTreeMap<usize, Op>,
+    diagnostics: Vec<Diagnostic>,
+    /// Whether any reachable jump has a target that is not a constant
+    indirect: bool,
+}
+
+/// Visit everything reachable from 0. With `returns`, the instruction after an unconditional
+/// jump is visited as well, as a guess that only warnings are reported for. `fixed` gives
+/// the value of cells known never to change.
+fn walk(program: &[isize], returns: bool, fixed: &dyn Fn(usize) -> Option<isize>) -> Walk {
+    let mut code = BTreeMap::new();
+    let mut diagnostics = vec![];
+    let mut indirect = false;
+    // Whether an arb ran on every path seen so far to each address, and whether every
+    // path there went through a guessed return
+    let mut based: BTreeMap<usize, (bool, bool)> = BTreeMap::new();
+    let mut pending = vec![(0, false, false)];
+
+    while let Some((address, arb, guess)) = pending.pop() {
+        let state = based
+            .get(&address)
+            .map_or((arb, guess), |seen| (seen.0 && arb, seen.1 && guess));
+        if based.insert(address, state) == Some(state) {
+            continue;
+        }
+        let (arb, guess) = state;
+        if address >= program.len() {
+            if !guess {
+                diagnostics.push(diagnostic(program, address, Lint::FallsOffEnd));
+            }
+            continue;
+        }
+        let op = match decode(program, address) {
+            Some(op) => op,
+            None => {
+                if !guess {
+                    let lint = Lint::InvalidOpcode(program[address]);
+                    diagnostics.push(diagnostic(program, address, lint));
+                }
+                continue;
+            }
+        };
+
+        if op.write_operand().map(|index| op.operands[index].mode) == Some(ParameterMode::Immediate)
+        {
+            diagnostics.push(diagnostic(program, address, Lint::ImmediateWrite));
+        }
+        if !arb
+            && op
+                .operands
+                .iter()
+                .any(|operand| operand.mode == ParameterMode::Relative)
+        {
+            diagnostics.push(diagnostic(program, address, Lint::RelativeBeforeArb));
+        }
+
+        let after = arb || op.opcode == 9;
+        match op.opcode {
+            99 => {}
+            5 | 6 => {
+                let condition = match op.operands[0] {
+                    Operand {
+                        mode: ParameterMode::Position,
+                        raw,
+                    } if raw >= 0 => fixed(raw as usize),
+                    _ => op.constant(0),
+                };
+                let taken = condition.map(|value| (op.opcode == 5) == (value != 0));
+                match op.constant(1) {
+                    Some(target) if target < 0 || target as usize >= program.len() => {
+                        if !guess {
+                            let lint = Lint::JumpOutOfBounds(target);
+                            diagnostics.push(diagnostic(program, address, lint));
+                        }
+                    }
+                    Some(target) if taken != Some(false) => {
+                        pending.push((target as usize, after, guess))
+                    }
+                    Some(_) => {}
+                    None => indirect = true,
+                }
+                if taken != Some(true) {
+                    pending.push((op.next(), after, guess));
+                } else if returns {
+                    pending.push((op.next(), after, true));
+                }
+            }
+            _ => pending.push((op.next(), after, guess)),
+        }
+        code.insert(address, op);
+    }
+
+    Walk {
+        code,
+        diagnostics,
+        indirect,
+    }
+}
+
+/// End of the unreachable block of instructions starting at `address`: it has to decode up
+/// to a halt or an unconditional jump without touching reachable code or known data, which
+/// keeps tables of numbers from looking like code.
+fn dead_block(
+    program: &[isize],
+    address: usize,
+    reachable: &HashSet<usize>,
+    data: &HashSet<usize>,
+) -> Option<usize> {
+    let mut next = address;
+    loop {
+        let op = decode(program, next)?;
+        if (op.address..op.next()).any(|cell| reachable.contains(&cell) || data.contains(&cell)) {
+            return None;
+        }
+        next = op.next();
+        let unconditional = match op.opcode {
+            99 => true,
+            5 | 6 => op.constant(0).map(|value| (op.opcode == 5) == (value != 0)) == Some(true),
+            _ => false,
+        };
+        if unconditional {
+            return Some(next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode::loader::parse_program;
+
+    fn lints(source: &str) -> Vec<(usize, Lint)> {
+        lint(&parse_program(source).unwrap())
+            .into_iter()
+            .map(|diagnostic| (diagnostic.address, diagnostic.lint))
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_programs() {
+        assert_eq!(lints("3,9,8,9,10,9,4,9,99,-1,8"), vec![]);
+        assert_eq!(
+            lints("109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_problems() {
+        assert_eq!(lints("11101,1,2,3,99"), vec![(0, Lint::ImmediateWrite)]);
+        assert_eq!(
+            lints("1105,1,9,104,1,99"),
+            vec![(0, Lint::JumpOutOfBounds(9)), (3, Lint::DeadCode(6))]
+        );
+        assert_eq!(lints("1,0,0,0"), vec![(4, Lint::FallsOffEnd)]);
+        assert_eq!(lints("1106,0,3,98"), vec![(3, Lint::InvalidOpcode(98))]);
+    }
+
+    #[test]
+    fn test_relative_base() {
+        // The output at 7 follows the arb at 5 unless the jump at 2 skips it
+        assert_eq!(
+            lints("3,20,1005,20,7,109,5,204,0,99"),
+            vec![(7, Lint::RelativeBeforeArb)]
+        );
+        assert_eq!(lints("3,20,1005,20,5,109,5,204,0,99"), vec![]);
+    }
+
+    #[test]
+    fn test_unchanging_cells() {
+        // The word at 7 becomes a halt before the jump reaches it
+        assert_eq!(lints("1101,0,99,7,1105,1,7,0"), vec![]);
+        // Nothing writes the condition at 0, so the jump to 5 is never taken
+        assert_eq!(lints("1006,0,5,99,0,98"), vec![]);
+        assert_eq!(
+            lints("3,0,1006,0,6,99,98"),
+            vec![(6, Lint::InvalidOpcode(98))]
+        );
+    }
+
+    #[test]
+    fn test_puzzle_inputs() {
+        for source in [
+            include_str!("../../input/2019/day5.txt"),
+            include_str!("../../input/2019/day9.txt"),
+            include_str!("../../input/2019/day11.txt"),
+            include_str!("../../input/2019/day15.txt"),
+        ] {
+            assert_eq!(lints(source), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let diagnostics = lint(&[11101, 1, 2, 3, 99]);
+        assert_eq!(
+            diagnostics[0].render("add.txt"),
+            "add.txt:0: warning: result stored through an immediate operand in `add 1, 2, 3` \
+             [immediate_write]"
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use super::binary::{BinaryError, Image};
+use super::CPU;
+
+/// Why a program could not be parsed, with the 1-based line and column it was found at
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// A token that is not a valid integer
+    InvalidNumber(String),
+    /// Two commas with nothing between them, or a leading comma
+    EmptyEntry,
+    /// Two numbers that are not separated by a comma
+    MissingComma,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::InvalidNumber(token) => write!(
+                f,
+                "{}:{}: invalid number `{}`",
+                self.line, self.column, token
+            ),
+            ParseErrorKind::EmptyEntry => write!(f, "{}:{}: empty entry", self.line, self.column),
+            ParseErrorKind::MissingComma => {
+                write!(f, "{}:{}: expected `,`", self.line, self.column)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Parse(ParseError),
+    Binary(BinaryError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "{}", error),
+            LoadError::Parse(error) => write!(f, "{}", error),
+            LoadError::Binary(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
+
+impl From<ParseError> for LoadError {
+    fn from(error: ParseError) -> Self {
+        LoadError::Parse(error)
+    }
+}
+
+impl From<BinaryError> for LoadError {
+    fn from(error: BinaryError) -> Self {
+        LoadError::Binary(error)
+    }
+}
+
+/// Parse comma separated intcode, ignoring whitespace and line breaks between entries and
+/// anything from a `#` to the end of its line. A single trailing comma is allowed.
+pub fn parse_program(source: &str) -> Result<Vec<isize>, ParseError> {
+    let mut program = vec![];
+    let mut token = String::new();
+    let mut token_start = (1, 1);
+    // Whether the last thing we saw was a finished number waiting for its comma
+    let mut awaiting_comma = false;
+
+    let mut line = 1;
+    let mut column = 0;
+    let mut in_comment = false;
+
+    for c in source.chars() {
+        column += 1;
+        if c == '\n' {
+            in_comment = false;
+        }
+        if in_comment {
+            continue;
+        }
+
+        match c {
+            '#' | ',' | ' ' | '\t' | '\r' | '\n' => {
+                if !token.is_empty() {
+                    program.push(parse_token(&token, token_start)?);
+                    token.clear();
+                    awaiting_comma = true;
+                }
+                if c == '#' {
+                    in_comment = true;
+                } else if c == ',' {
+                    if !awaiting_comma {
+                        return Err(ParseError {
+                            line,
+                            column,
+                            kind: ParseErrorKind::EmptyEntry,
+                        });
+                    }
+                    awaiting_comma = false;
+                }
+            }
+            _ => {
+                if token.is_empty() {
+                    if awaiting_comma {
+                        return Err(ParseError {
+                            line,
+                            column,
+                            kind: ParseErrorKind::MissingComma,
+                        });
+                    }
+                    token_start = (line, column);
+                }
+                token.push(c);
+            }
+        }
+
+        if c == '\n' {
+            line += 1;
+            column = 0;
+        }
+    }
+
+    if !token.is_empty() {
+        program.push(parse_token(&token, token_start)?);
+    }
+
+    Ok(program)
+}
+
+fn parse_token(token: &str, (line, column): (usize, usize)) -> Result<isize, ParseError> {
+    token.parse::<isize>().map_err(|_| ParseError {
+        line,
+        column,
+        kind: ParseErrorKind::InvalidNumber(token.to_string()),
+    })
+}
+
+impl CPU {
+    /// Fallible version of `CPU::new` that reports where parsing failed
+    pub fn try_new(program: &str) -> Result<CPU, ParseError> {
+        Ok(CPU::from_memory(parse_program(program)?))
+    }
+
+    /// Load either comma separated text or the binary format of `Image::to_bytes`
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<CPU, LoadError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        if Image::is_binary(&bytes) {
+            return Ok(CPU::from_binary(&bytes)?);
+        }
+        let source = String::from_utf8(bytes)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        Ok(CPU::try_new(&source)?)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<CPU, LoadError> {
+        CPU::from_reader(File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_whitespace_and_comments() {
+        let source = "# add two numbers\n1, 5, 6, 0,  # the add\n\t99,\n7,8,\n";
+        assert_eq!(parse_program(source), Ok(vec![1, 5, 6, 0, 99, 7, 8]));
+    }
+
+    #[test]
+    fn test_puzzle_input() {
+        assert_eq!(parse_program("1,0,0,3,99\n"), Ok(vec![1, 0, 0, 3, 99]));
+        assert_eq!(parse_program("-1,+2"), Ok(vec![-1, 2]));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse_program("1,2,\n3,x4,5"),
+            Err(ParseError {
+                line: 2,
+                column: 3,
+                kind: ParseErrorKind::InvalidNumber("x4".to_string())
+            })
+        );
+        assert_eq!(
+            parse_program("1,,2"),
+            Err(ParseError {
+                line: 1,
+                column: 3,
+                kind: ParseErrorKind::EmptyEntry
+            })
+        );
+        assert_eq!(
+            parse_program("1,2\n3"),
+            Err(ParseError {
+                line: 2,
+                column: 1,
+                kind: ParseErrorKind::MissingComma
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let mut cpu = CPU::from_reader("104,7,99 # print 7".as_bytes()).expect("Valid program");
+        cpu.run(None).expect("Program should halt");
+        assert_eq!(cpu.get_output(), vec![7]);
+    }
+}
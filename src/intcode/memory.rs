@@ -0,0 +1,206 @@
+//! Copy-on-write CPU memory. The cells live in fixed size pages behind reference counts, so
+//! cloning a memory (and with it a CPU) only copies the page table. A page is copied the
+//! first time a clone writes to it, and pages of zeros added by `resize` all share one
+//! page until they are written.
+
+use std::fmt;
+use std::ops::Index;
+use std::sync::Arc;
+
+use super::decode::{self, Op};
+
+/// Number of cells in a page
+pub const PAGE_SIZE: usize = 256;
+
+#[derive(Clone)]
+pub struct Memory {
+    /// Every page is `PAGE_SIZE` long, cells past `len` in the last page are kept at 0
+    pages: Vec<Arc<Vec<isize>>>,
+    len: usize,
+}
+
+impl Memory {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, address: usize) -> Option<isize> {
+        if address < self.len {
+            Some(self.pages[address / PAGE_SIZE][address % PAGE_SIZE])
+        } else {
+            None
+        }
+    }
+
+    /// Store `value` at `address`, copying its page first if it is shared
+    pub fn set(&mut self, address: usize, value: isize) {
+        assert!(
+            address < self.len,
+            "Address {} is out of bounds for memory of size {}",
+            address,
+            self.len
+        );
+        Arc::make_mut(&mut self.pages[address / PAGE_SIZE])[address % PAGE_SIZE] = value;
+    }
+
+    /// Grow with zeros or shrink to `size` cells
+    pub fn resize(&mut self, size: usize) {
+        if size < self.len {
+            self.pages.truncate(size.div_ceil(PAGE_SIZE));
+            let used = size % PAGE_SIZE;
+            if used != 0 {
+                if let Some(last) = self.pages.last_mut() {
+                    Arc::make_mut(last)[used..]
+                        .iter_mut()
+                        .for_each(|cell| *cell = 0);
+                }
+            }
+        } else {
+            let zeros = Arc::new(vec![0; PAGE_SIZE]);
+            self.pages
+                .resize_with(size.div_ceil(PAGE_SIZE), || zeros.clone());
+        }
+        self.len = size;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = isize> + '_ {
+        self.pages
+            .iter()
+            .flat_map(|page| page.iter().cloned())
+            .take(self.len)
+    }
+
+    pub fn to_vec(&self) -> Vec<isize> {
+        self.iter().collect()
+    }
+
+    /// Number of pages this memory still shares with `other`
+    pub fn shared_pages(&self, other: &Memory) -> usize {
+        self.pages
+            .iter()
+            .zip(other.pages.iter())
+            .filter(|(mine, theirs)| Arc::ptr_eq(mine, theirs))
+            .count()
+    }
+
+    /// Whether the page holding `address` is the same one in `other`, which means every cell
+    /// in it is equal
+    pub(crate) fn same_page(&self, other: &Memory, address: usize) -> bool {
+        match (
+            self.pages.get(address / PAGE_SIZE),
+            other.pages.get(address / PAGE_SIZE),
+        ) {
+            (Some(mine), Some(theirs)) => Arc::ptr_eq(mine, theirs),
+            _ => false,
+        }
+    }
+
+    /// Decode the instruction at `address`
+    pub fn decode(&self, address: usize) -> Option<Op> {
+        decode::decode_with(|address| self.get(address), address)
+    }
+}
+
+impl From<Vec<isize>> for Memory {
+    fn from(cells: Vec<isize>) -> Memory {
+        let len = cells.len();
+        let pages = cells
+            .chunks(PAGE_SIZE)
+            .map(|chunk| {
+                let mut page = chunk.to_vec();
+                page.resize(PAGE_SIZE, 0);
+                Arc::new(page)
+            })
+            .collect();
+        Memory { pages, len }
+    }
+}
+
+impl Index<usize> for Memory {
+    type Output = isize;
+
+    fn index(&self, address: usize) -> &isize {
+        assert!(
+            address < self.len,
+            "Address {} is out of bounds for memory of size {}",
+            address,
+            self.len
+        );
+        &self.pages[address / PAGE_SIZE][address % PAGE_SIZE]
+    }
+}
+
+impl PartialEq for Memory {
+    fn eq(&self, other: &Memory) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode::CPU;
+
+    #[test]
+    fn test_copy_on_write() {
+        let mut memory = Memory::from((0..1000).collect::<Vec<isize>>());
+        let pages = memory.pages.len();
+        let mut fork = memory.clone();
+        assert_eq!(fork.shared_pages(&memory), pages);
+
+        fork.set(300, -1);
+        assert_eq!(fork.shared_pages(&memory), pages - 1);
+        assert_eq!(memory[300], 300);
+        assert_eq!(fork[300], -1);
+
+        memory.set(301, -2);
+        assert_eq!(fork.shared_pages(&memory), pages - 1);
+        assert_eq!(fork[301], 301);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut memory = Memory::from(vec![1; PAGE_SIZE + 10]);
+        memory.resize(5);
+        assert_eq!(memory.to_vec(), vec![1; 5]);
+        memory.resize(4 * PAGE_SIZE);
+        assert_eq!(memory.len(), 4 * PAGE_SIZE);
+        assert_eq!(memory.iter().sum::<isize>(), 5);
+        assert_eq!(memory.get(4 * PAGE_SIZE), None);
+
+        // The new pages of zeros are one shared page until written
+        let zeros = &memory.pages[1];
+        assert!(memory.pages[2..]
+            .iter()
+            .all(|page| Arc::ptr_eq(page, zeros)));
+        memory.set(2 * PAGE_SIZE, 7);
+        assert!(Arc::ptr_eq(&memory.pages[1], &memory.pages[3]));
+        assert_eq!(memory[2 * PAGE_SIZE], 7);
+        assert_eq!(memory[3 * PAGE_SIZE], 0);
+    }
+
+    #[test]
+    fn test_forked_cpus() {
+        let mut cpu = CPU::new(include_str!("../../input/2019/day9.txt"));
+        cpu.set_memory_size(64 * PAGE_SIZE);
+        let mut forks: Vec<CPU> = (0..1000).map(|_| cpu.clone()).collect();
+        for (index, fork) in forks.iter_mut().enumerate() {
+            fork.set_memory(5000, index as isize);
+        }
+        assert!(forks
+            .iter()
+            .all(|fork| fork.memory.shared_pages(&cpu.memory) == 63));
+        assert_eq!(forks[999].get_memory(5000), 999);
+        assert_eq!(cpu.get_memory(5000), 0);
+    }
+}
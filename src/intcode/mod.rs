@@ -1,55 +1,66 @@
 use itertools::Itertools;
 use rayon::prelude::*;
 use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::sync::Arc;
 
-macro_rules! address_or_value {
-    ($iter_name:ident, $cpu:ident, $expression:expr) => {
-        match $iter_name.next() {
-            Some(val) => {
-                match val.to_digit(10).expect("Parameter mode is not a digit!") {
-                    0 => {
-                        //debug_print!("Position mode!");
-                        $cpu.get_value($expression)
-                    }
-                    1 => {
-                        //debug_print!("Immediate mode!");
-                        $cpu.get_memory($expression)
-                    }
-                    2 => {
-                        //debug_print!("Relative mode!");
-                        $cpu.get_relative($expression)
-                    }
-                    _ => unreachable!(),
-                }
-            }
-            None => {
-                //debug_print!("Position mode!");
-                $cpu.get_value($expression)
-            }
-        }
-    };
-}
+use crate::trace::{self, Event, SharedTracer, Verbosity};
+use calls::CallStack;
+use coverage::Coverage;
+use cycle::CycleDetector;
+use decode::ParameterMode;
+use extension::{Flow, OpcodeRegistry};
+use isa::IsaProfile;
+use memory::Memory;
+use protect::MemoryMap;
+use taint::TaintTracker;
+use uninit::InitTracker;
 
-macro_rules! get_location {
-    ($iter_name:ident, $cpu:ident, $expression:expr) => {
-        match $iter_name.next() {
-            Some('2') => $cpu.get_memory($expression) + $cpu.relative_base,
-            _ => $cpu.get_memory($expression),
-        }
-    };
-}
+pub mod asynchronous;
+pub mod binary;
+pub mod calls;
+pub mod coverage;
+mod cycle;
+pub mod decode;
+pub mod decompile;
+pub mod diff;
+pub mod explore;
+pub mod extension;
+pub mod gdb;
+pub mod isa;
+pub mod lint;
+pub mod loader;
+pub mod memory;
+pub mod optimize;
+pub mod protect;
+pub mod script;
+pub mod search;
+pub mod taint;
+pub mod threaded;
+pub mod uninit;
 
 pub type CpuResult<T> = std::result::Result<T, CpuError>;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CpuError {
     InvalidOpcode(isize, usize),
-    InvalidLastInstruction,
     InvalidUserInput,
     InvalidOutputGenerated,
+    StepLimitReached(usize),
+    LoopDetected(usize),
+    /// Address read and the instruction reading it
+    UninitializedRead(usize, usize),
+    /// Address and instruction of an access a protected region forbids
+    ReadViolation(usize, usize),
+    WriteViolation(usize, usize),
+    ExecuteViolation(usize, usize),
+    /// Instruction word and address of an instruction the ISA profile does not include
+    OpcodeNotInProfile(isize, usize),
+    ModeNotInProfile(isize, usize),
+    /// Address outside memory and the instruction using it
+    AddressOutOfBounds(isize, usize),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ExitReason {
     Halt,
     InputRequired,
@@ -58,182 +69,258 @@ pub enum ExitReason {
 
 #[derive(Debug, Clone)]
 pub struct CPU {
-    memory: Vec<isize>,
+    memory: Memory,
+    /// Length of the program image the CPU was built from
+    image_size: usize,
     instruction_pointer: usize,
     relative_base: isize,
-    last_instruction: Option<Instruction>,
     output: Vec<isize>,
 
     exit_on_output: bool,
+    tracer: SharedTracer,
+    steps: usize,
+    step_limit: Option<usize>,
+    coverage: Option<Coverage>,
+    cycle_detector: Option<CycleDetector>,
+    extensions: Option<Arc<OpcodeRegistry>>,
+    init_tracker: Option<InitTracker>,
+    memory_map: Option<MemoryMap>,
+    profile: IsaProfile,
+    call_stack: Option<CallStack>,
+    taint: Option<TaintTracker>,
 }
 
 impl CPU {
     pub fn new(program: &str) -> CPU {
+        match loader::parse_program(program) {
+            Ok(memory) => CPU::from_memory(memory),
+            Err(error) => panic!("Invalid intcode program : {}", error),
+        }
+    }
+
+    /// Build a CPU from an already parsed program image
+    pub fn from_memory(memory: Vec<isize>) -> CPU {
         CPU {
-            memory: program
-                .trim()
-                .split(",")
-                .map(|x| x.parse::<isize>().unwrap())
-                .collect(),
+            image_size: memory.len(),
+            memory: Memory::from(memory),
             instruction_pointer: 0,
             relative_base: 0,
-            last_instruction: None,
             output: vec![],
             exit_on_output: false,
+            tracer: trace::global(),
+            steps: 0,
+            step_limit: None,
+            coverage: None,
+            cycle_detector: None,
+            extensions: None,
+            init_tracker: None,
+            memory_map: None,
+            profile: IsaProfile::default(),
+            call_stack: None,
+            taint: None,
         }
     }
 
     pub fn run(&mut self, input: Option<&str>) -> CpuResult<ExitReason> {
-        let mut user_input = input.unwrap_or("").trim().lines();
+        let mut user_input = input.unwrap_or("").trim().lines().peekable();
         loop {
-            match self.parse()? {
-                Instruction::Add(left, right, location) => {
-                    debug_print!("Add : {} + {} @ {}", left, right, location);
-                    self.last_instruction = Some(Instruction::Add(left, right, location));
-                    self.set_memory(location as usize, left + right);
+            if let Some(limit) = self.step_limit {
+                if self.steps >= limit {
+                    return Err(CpuError::StepLimitReached(self.instruction_pointer));
                 }
-                Instruction::Mult(left, right, location) => {
-                    debug_print!("Mult : {} * {} @ {}", left, right, location);
-                    self.last_instruction = Some(Instruction::Mult(left, right, location));
-                    self.set_memory(location as usize, left * right);
+            }
+            self.steps += 1;
+            if let Some(detector) = self.cycle_detector.as_mut() {
+                if detector.repeats(self.steps, self.instruction_pointer, self.relative_base) {
+                    return Err(CpuError::LoopDetected(self.instruction_pointer));
                 }
-                Instruction::In(location) => {
-                    debug_print!("In : @ {}", location);
-                    self.last_instruction = Some(Instruction::In(location));
-                    self.set_memory(
-                        location as usize,
-                        if input.is_some() {
-                            match user_input.next() {
-                                Some(val) => val.trim().parse::<isize>().unwrap(),
-                                None => return Ok(ExitReason::InputRequired),
+            }
+            if self.instruction_pointer >= self.memory.len() {
+                let ip = self.instruction_pointer;
+                return Err(CpuError::AddressOutOfBounds(ip as isize, ip));
+            }
+            self.check_protection()?;
+            self.check_profile()?;
+            self.check_uninitialized_reads()?;
+            if let Some((registry, opcode)) = self.pending_extension() {
+                let extension = registry
+                    .get(opcode)
+                    .expect("Pending extension is registered");
+                let ip = self.instruction_pointer;
+                self.tracer
+                    .emit(Verbosity::Trace, || Event::InstructionExecuted {
+                        ip,
+                        text: format!("Ext {} : {}", opcode, extension.name),
+                    });
+                if let Some(coverage) = self.coverage.as_mut() {
+                    coverage.record(&self.memory, ip, self.relative_base);
+                }
+                match self.execute_extension(extension)? {
+                    Flow::Next => {}
+                    Flow::Jump(target) => self.instruction_pointer = target,
+                    Flow::Halt => {
+                        self.tracer.emit(Verbosity::Info, || Event::Halted { ip });
+                        return Ok(ExitReason::Halt);
+                    }
+                }
+                continue;
+            }
+
+            let op = self
+                .memory
+                .decode(self.instruction_pointer)
+                .ok_or_else(|| {
+                    CpuError::InvalidOpcode(
+                        self.get_memory(self.instruction_pointer),
+                        self.instruction_pointer,
+                    )
+                })?;
+            let values = self.resolve(&op)?;
+            let ip = self.instruction_pointer;
+            let next = op.next();
+            self.propagate_taint();
+            self.tracer
+                .emit(Verbosity::Trace, || Event::InstructionExecuted {
+                    ip,
+                    text: describe(op.opcode, &values),
+                });
+            if let Some(coverage) = self.coverage.as_mut() {
+                // An input instruction that is about to give up runs again on resume
+                let starved = op.opcode == 3 && user_input.peek().is_none();
+                if !starved {
+                    coverage.record(&self.memory, ip, self.relative_base);
+                }
+            }
+            match (op.opcode, values.as_slice()) {
+                (1, &[left, right, location]) => self.write(location as usize, left + right),
+                (2, &[left, right, location]) => self.write(location as usize, left * right),
+                (3, &[location]) => {
+                    let value = match user_input.next() {
+                        Some(val) => val.trim().parse::<isize>().unwrap(),
+                        None => {
+                            self.tracer
+                                .emit(Verbosity::Debug, || Event::InputRequired { ip });
+                            if let Some(detector) = self.cycle_detector.as_mut() {
+                                detector.reset();
                             }
-                        } else {
                             return Ok(ExitReason::InputRequired);
-                        },
-                    );
+                        }
+                    };
+                    self.tracer.emit(Verbosity::Debug, || Event::InputConsumed {
+                        ip,
+                        address: location as usize,
+                        value,
+                    });
+                    if let Some(detector) = self.cycle_detector.as_mut() {
+                        detector.reset();
+                    }
+                    self.taint_input(location as usize);
+                    self.write(location as usize, value);
                 }
-                Instruction::Out(value) => {
-                    debug_print!("Out : {}", value);
-                    self.last_instruction = Some(Instruction::Out(value));
+                (4, &[value]) => {
+                    self.tracer
+                        .emit(Verbosity::Debug, || Event::OutputProduced { ip, value });
+                    self.taint_output(value);
                     self.output.push(value);
                     if self.exit_on_output {
-                        self.increment_ip()?;
+                        self.instruction_pointer = next;
                         return Ok(ExitReason::OutputGenerated);
                     }
                 }
-                Instruction::JumpIfTrue(value, new_ip) => {
-                    debug_print!("JIT : {} to {}", value, new_ip);
-                    self.last_instruction = Some(Instruction::JumpIfTrue(value, new_ip));
-                    if value != 0 {
-                        self.instruction_pointer = new_ip as usize;
-                    }
+                (5, &[value, new_ip]) if value != 0 => {
+                    self.instruction_pointer = self.address(new_ip, ip)?;
+                    self.record_jump(ip, new_ip as usize);
+                    continue;
                 }
-                Instruction::JumpIfFalse(value, new_ip) => {
-                    debug_print!("JIF : {} to {}", value, new_ip);
-                    self.last_instruction = Some(Instruction::JumpIfFalse(value, new_ip));
-                    if value == 0 {
-                        self.instruction_pointer = new_ip as usize;
-                    }
+                (6, &[0, new_ip]) => {
+                    self.instruction_pointer = self.address(new_ip, ip)?;
+                    self.record_jump(ip, new_ip as usize);
+                    continue;
                 }
-                Instruction::LessThan(left, right, location) => {
-                    debug_print!("LT : {} < {} @ {}", left, right, location);
-                    self.last_instruction = Some(Instruction::LessThan(left, right, location));
-                    if left < right {
-                        self.set_memory(location as usize, 1);
-                    } else {
-                        self.set_memory(location as usize, 0);
-                    }
+                (5, _) | (6, _) => {}
+                (7, &[left, right, location]) => {
+                    self.write(location as usize, (left < right) as isize)
                 }
-                Instruction::Equal(left, right, location) => {
-                    debug_print!("EQ : {} == {} @ {}", left, right, location);
-                    self.last_instruction = Some(Instruction::Equal(left, right, location));
-                    if left == right {
-                        self.set_memory(location as usize, 1);
-                    } else {
-                        self.set_memory(location as usize, 0);
-                    }
-                }
-                Instruction::AdjustRelativeBase(value) => {
-                    debug_print!("AdjustRelBase : {} + {}", self.relative_base, value);
-                    self.last_instruction = Some(Instruction::AdjustRelativeBase(value));
-                    self.relative_base += value;
+                (8, &[left, right, location]) => {
+                    self.write(location as usize, (left == right) as isize)
                 }
-                Instruction::Halt => {
-                    debug_print!("Halt");
-                    self.last_instruction = Some(Instruction::Halt);
+                (9, &[value]) => self.relative_base += value,
+                // 99, the decoder knows no other opcode
+                _ => {
+                    self.tracer.emit(Verbosity::Info, || Event::Halted { ip });
                     return Ok(ExitReason::Halt);
                 }
             }
-            self.increment_ip()?;
+            self.instruction_pointer = next;
         }
     }
 
-    fn parse(&self) -> CpuResult<Instruction> {
-        Instruction::parse(&self)
+    /// Values of the operands of `op`, or the address for the one it writes to
+    fn resolve(&self, op: &decode::Op) -> CpuResult<Vec<isize>> {
+        op.operands
+            .iter()
+            .enumerate()
+            .map(|(index, operand)| {
+                let address = match operand.mode {
+                    ParameterMode::Immediate => return Ok(operand.raw),
+                    ParameterMode::Position => operand.raw,
+                    ParameterMode::Relative => self.relative_base + operand.raw,
+                };
+                let cell = self.address(address, op.address)?;
+                if op.write_operand() == Some(index) {
+                    Ok(address)
+                } else {
+                    Ok(self.get_memory(cell))
+                }
+            })
+            .collect()
     }
 
-    fn increment_ip(&mut self) -> CpuResult<()> {
-        if let Some(last_instr) = self.last_instruction {
-            match last_instr {
-                Instruction::Add(_, _, _) => {
-                    self.instruction_pointer += 4;
-                }
-                Instruction::Mult(_, _, _) => {
-                    self.instruction_pointer += 4;
-                }
-                Instruction::In(_) => {
-                    self.instruction_pointer += 2;
-                }
-                Instruction::Out(_) => {
-                    self.instruction_pointer += 2;
-                }
-                Instruction::JumpIfTrue(value, _) => {
-                    if value != 0 {
-                    } else {
-                        self.instruction_pointer += 3;
-                    }
-                }
-                Instruction::JumpIfFalse(value, _) => {
-                    if value == 0 {
-                    } else {
-                        self.instruction_pointer += 3;
-                    }
-                }
-                Instruction::LessThan(_, _, _) => {
-                    self.instruction_pointer += 4;
-                }
-                Instruction::Equal(_, _, _) => {
-                    self.instruction_pointer += 4;
-                }
-                Instruction::AdjustRelativeBase(_) => {
-                    self.instruction_pointer += 2;
-                }
-                Instruction::Halt => {
-                    self.instruction_pointer += 1;
-                }
-            }
-            Ok(())
+    /// `address` as an index into memory, if the instruction at `ip` may use it
+    fn address(&self, address: isize, ip: usize) -> CpuResult<usize> {
+        if address >= 0 && (address as usize) < self.memory.len() {
+            Ok(address as usize)
         } else {
-            Err(CpuError::InvalidLastInstruction)
+            Err(CpuError::AddressOutOfBounds(address, ip))
         }
     }
 
     /// Get the value at a memory address
-    fn get_memory(&self, address: usize) -> isize {
+    pub fn get_memory(&self, address: usize) -> isize {
         self.memory[address]
     }
 
-    /// Convienient wrapper around `get_memory` that will get a value instead of a position if needed
-    fn get_value(&self, address: usize) -> isize {
-        self.get_memory(self.get_memory(address) as usize)
+    /// Store a value from outside the program. Loop detection treats this like new input.
+    pub fn set_memory(&mut self, address: usize, value: isize) {
+        self.write(address, value);
+        self.taint_patch(address);
+        if let Some(detector) = self.cycle_detector.as_mut() {
+            detector.reset();
+        }
+    }
+
+    fn write(&mut self, address: usize, value: isize) {
+        if let Some(detector) = self.cycle_detector.as_mut() {
+            detector.write(address, self.memory[address], value);
+        }
+        if let Some(tracker) = self.init_tracker.as_mut() {
+            tracker.write(address);
+        }
+        self.memory.set(address, value);
     }
 
-    fn get_relative(&self, address: usize) -> isize {
-        self.get_memory((self.relative_base + self.get_memory(address)) as usize)
+    pub fn set_tracer(&mut self, tracer: SharedTracer) {
+        self.tracer = tracer;
     }
 
-    pub fn set_memory(&mut self, address: usize, value: isize) {
-        self.memory[address] = value;
+    /// Fail with `CpuError::StepLimitReached` instead of executing more than `limit` instructions
+    pub fn set_step_limit(&mut self, limit: usize) {
+        self.step_limit = Some(limit);
+    }
+
+    /// Number of instructions executed so far
+    pub fn get_steps(&self) -> usize {
+        self.steps
     }
 
     pub fn set_exit_on_output(&mut self) {
@@ -245,7 +332,13 @@ impl CPU {
     }
 
     pub fn set_memory_size(&mut self, size: usize) {
-        self.memory.resize(size, 0);
+        self.memory.resize(size);
+        if let Some(tracker) = self.init_tracker.as_mut() {
+            tracker.truncate(size);
+        }
+        if let Some(detector) = self.cycle_detector.as_mut() {
+            detector.rehash(&self.memory);
+        }
     }
 
     pub fn get_output(&self) -> Vec<isize> {
@@ -255,76 +348,104 @@ impl CPU {
     pub fn get_last_output(&self) -> Option<&isize> {
         self.output.last()
     }
+
+    /// The whole memory, cheap to clone and keep as a snapshot
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// The instruction about to run, with its raw operands and parameter modes
+    pub fn current_instruction(&self) -> Option<decode::Op> {
+        self.memory.decode(self.instruction_pointer)
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Instruction {
-    Add(isize, isize, isize),
-    Mult(isize, isize, isize),
-    In(isize),
-    Out(isize),
-    JumpIfTrue(isize, isize),
-    JumpIfFalse(isize, isize),
-    LessThan(isize, isize, isize),
-    Equal(isize, isize, isize),
-    AdjustRelativeBase(isize),
-    Halt,
+/// How the trace shows an instruction, with its operands resolved
+fn describe(opcode: isize, values: &[isize]) -> String {
+    match (opcode, values) {
+        (1, [left, right, location]) => format!("Add : {} + {} @ {}", left, right, location),
+        (2, [left, right, location]) => format!("Mult : {} * {} @ {}", left, right, location),
+        (3, [location]) => format!("In : @ {}", location),
+        (4, [value]) => format!("Out : {}", value),
+        (5, [value, new_ip]) => format!("JIT : {} to {}", value, new_ip),
+        (6, [value, new_ip]) => format!("JIF : {} to {}", value, new_ip),
+        (7, [left, right, location]) => format!("LT : {} < {} @ {}", left, right, location),
+        (8, [left, right, location]) => format!("EQ : {} == {} @ {}", left, right, location),
+        (9, [value]) => format!("AdjustRelBase : {}", value),
+        _ => "Halt".to_string(),
+    }
 }
 
-impl Instruction {
-    fn parse(cpu: &CPU) -> CpuResult<Instruction> {
-        let mem = cpu.get_memory(cpu.instruction_pointer);
-        let flags = (mem / 100).to_string();
-        let mut flags_iter = flags.chars().rev();
-        let instr = match mem % 100 {
-            1 => Instruction::Add(
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 1),
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 2),
-                get_location!(flags_iter, cpu, cpu.instruction_pointer + 3),
-            ),
-            2 => Instruction::Mult(
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 1),
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 2),
-                get_location!(flags_iter, cpu, cpu.instruction_pointer + 3),
-            ),
-            3 => Instruction::In(get_location!(flags_iter, cpu, cpu.instruction_pointer + 1)),
-            4 => Instruction::Out(address_or_value!(
-                flags_iter,
-                cpu,
-                cpu.instruction_pointer + 1
-            )),
-            5 => Instruction::JumpIfTrue(
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 1),
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 2),
-            ),
-            6 => Instruction::JumpIfFalse(
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 1),
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 2),
-            ),
-            7 => Instruction::LessThan(
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 1),
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 2),
-                get_location!(flags_iter, cpu, cpu.instruction_pointer + 3),
-            ),
-            8 => Instruction::Equal(
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 1),
-                address_or_value!(flags_iter, cpu, cpu.instruction_pointer + 2),
-                get_location!(flags_iter, cpu, cpu.instruction_pointer + 3),
-            ),
-            9 => Instruction::AdjustRelativeBase(address_or_value!(
-                flags_iter,
-                cpu,
-                cpu.instruction_pointer + 1
-            )),
-            99 => Instruction::Halt,
-            _ => {
-                return Err(CpuError::InvalidOpcode(
-                    cpu.get_memory(cpu.instruction_pointer),
-                    cpu.instruction_pointer,
-                ))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::MemoryTracer;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_trace_events() {
+        let tracer = Arc::new(MemoryTracer::new(Verbosity::Debug));
+        let mut cpu = CPU::new("3,0,4,0,99");
+        cpu.set_tracer(tracer.clone());
+
+        assert!(matches!(cpu.run(None), Ok(ExitReason::InputRequired)));
+        assert!(matches!(cpu.run(Some("5")), Ok(ExitReason::Halt)));
+        assert_eq!(
+            tracer.events(),
+            vec![
+                Event::InputRequired { ip: 0 },
+                Event::InputConsumed {
+                    ip: 0,
+                    address: 0,
+                    value: 5
+                },
+                Event::OutputProduced { ip: 2, value: 5 },
+                Event::Halted { ip: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_instructions() {
+        let tracer = Arc::new(MemoryTracer::new(Verbosity::Trace));
+        let mut cpu = CPU::new("1101,2,3,5,99,0");
+        cpu.set_tracer(tracer.clone());
+        cpu.run(None).expect("Program should halt");
+
+        let events = tracer.events();
+        assert_eq!(
+            events[0],
+            Event::InstructionExecuted {
+                ip: 0,
+                text: "Add : 2 + 3 @ 5".to_string()
             }
-        };
+        );
+        assert_eq!(events.len(), 3);
+    }
 
-        Ok(instr)
+    #[test]
+    fn test_malformed_modes() {
+        // Mode digits past the last operand, and digits that are not modes
+        for (program, word) in [
+            ("10099", 10099),
+            ("301,0,0,0,99", 301),
+            ("1901,0,0,0,99", 1901),
+        ] {
+            let mut cpu = CPU::new(program);
+            assert_eq!(cpu.run(None), Err(CpuError::InvalidOpcode(word, 0)));
+        }
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        for (program, error) in [
+            ("4,50,99", CpuError::AddressOutOfBounds(50, 0)),
+            ("1101,1,1,-1,99", CpuError::AddressOutOfBounds(-1, 0)),
+            ("109,-10,204,3,99", CpuError::AddressOutOfBounds(-7, 2)),
+            ("1105,1,-4", CpuError::AddressOutOfBounds(-4, 0)),
+            ("1101,1,1,0", CpuError::AddressOutOfBounds(4, 4)),
+        ] {
+            assert_eq!(CPU::new(program).run(None), Err(error));
+        }
     }
 }
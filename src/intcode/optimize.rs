@@ -0,0 +1,537 @@
+//! Program to program optimization of intcode, plus a bounded equivalence check.
+//!
+//! Absolute addresses are everywhere in intcode (jump targets, data cells, return addresses
+//! stored as immediates), so every rewrite keeps each instruction at its original address
+//! and length. The passes are:
+//!
+//! * constant folding: arithmetic and compares on immediates become a single `Add` of a
+//!   constant, and jumps with an immediate condition become unconditional or a no-op
+//! * read-only inlining: position mode reads of cells nothing can write become immediates
+//! * jump threading: a jump to an unconditional jump goes straight to its final target
+//! * dead code dropping: unreachable instruction cells that are never read are zeroed
+//!
+//! Instructions that the program reads or writes as data are never touched. When the
+//! program can reach memory through addresses that are not known statically (relative mode,
+//! indirect jumps, self-modifying code) inlining and dropping are skipped entirely. When a
+//! write can land on code nothing is rewritten at all. Relative mode writes only count when
+//! the relative base is not a stack: a program whose first `109` points it past the image is
+//! trusted to keep its relative writes there.
+
+use std::collections::{BTreeMap, HashSet};
+
+use itertools::Itertools;
+
+use super::decode::{decode, Op, Operand, ParameterMode};
+use super::{CpuError, CpuResult, ExitReason, CPU};
+
+/// An optimized program and how many of each rewrite went into it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimization {
+    pub program: Vec<isize>,
+    pub folded: usize,
+    pub inlined: usize,
+    pub threaded: usize,
+    pub dropped: usize,
+}
+
+impl Optimization {
+    /// The optimized program as comma separated intcode
+    pub fn to_source(&self) -> String {
+        self.program.iter().join(",")
+    }
+}
+
+struct Analysis {
+    /// Instructions reachable from address 0
+    code: BTreeMap<usize, Op>,
+    /// Cells accessed as data through position mode
+    data: HashSet<usize>,
+    /// Cells written through position mode
+    written: HashSet<usize>,
+    /// Whether memory can be reached through addresses that are not known statically
+    dynamic: bool,
+    /// Whether any write can land on an instruction
+    rewrites_code: bool,
+}
+
+impl Analysis {
+    fn new(program: &[isize]) -> Analysis {
+        let mut code = BTreeMap::new();
+        let mut dynamic = false;
+        let mut unknown_code = false;
+        let mut pending = vec![0];
+        while let Some(address) = pending.pop() {
+            if code.contains_key(&address) {
+                continue;
+            }
+            let op = match decode(program, address) {
+                Some(op) => op,
+                None => {
+                    // Whatever runs here is written at runtime
+                    dynamic = true;
+                    unknown_code |= address < program.len();
+                    continue;
+                }
+            };
+            if op
+                .operands
+                .iter()
+                .any(|operand| operand.mode == ParameterMode::Relative)
+            {
+                dynamic = true;
+            }
+            match op.opcode {
+                99 => {}
+                5 | 6 => match (op.constant(0), op.constant(1)) {
+                    (condition, Some(target)) if target >= 0 => {
+                        let taken = condition.map(|value| (op.opcode == 5) == (value != 0));
+                        if taken != Some(false) {
+                            pending.push(target as usize);
+                        }
+                        // Returns land right after unconditional jumps that were calls
+                        if taken != Some(true) || dynamic {
+                            pending.push(op.next());
+                        }
+                    }
+                    _ => {
+                        dynamic = true;
+                        pending.push(op.next());
+                    }
+                },
+                _ => pending.push(op.next()),
+            }
+            code.insert(address, op);
+        }
+
+        let mut data = HashSet::new();
+        let mut written = HashSet::new();
+        for op in code.values() {
+            let write = op.write_operand();
+            let jump_target = if op.opcode == 5 || op.opcode == 6 {
+                Some(1)
+            } else {
+                None
+            };
+            for (index, operand) in op.operands.iter().enumerate() {
+                let is_write = write == Some(index);
+                if operand.raw < 0 || Some(index) == jump_target {
+                    continue;
+                }
+                if operand.mode == ParameterMode::Position
+                    || (is_write && operand.mode == ParameterMode::Immediate)
+                {
+                    data.insert(operand.raw as usize);
+                    if is_write {
+                        written.insert(operand.raw as usize);
+                    }
+                }
+            }
+        }
+        // The relative base is a stack when its first adjustment points it past the image
+        let base = code
+            .values()
+            .find(|op| op.opcode == 9)
+            .and_then(|op| op.constant(0));
+        let stack = matches!(base, Some(base) if base >= program.len() as isize);
+        let rewrites_code = unknown_code
+            || code.values().any(|op| {
+                (op.address..op.next()).any(|cell| written.contains(&cell))
+                    || (!stack
+                        && op.write_operand().map(|index| op.operands[index].mode)
+                            == Some(ParameterMode::Relative))
+            });
+        // Self-modifying code makes every operand it rewrites unknown
+        if rewrites_code {
+            dynamic = true;
+        }
+
+        Analysis {
+            code,
+            data,
+            written,
+            dynamic,
+            rewrites_code,
+        }
+    }
+
+    /// Whether an instruction's own cells are read or written as data
+    fn is_data(&self, op: &Op) -> bool {
+        (op.address..op.next()).any(|cell| self.data.contains(&cell))
+    }
+}
+
+fn store(program: &mut [isize], op: &Op) -> bool {
+    let words = op.encode();
+    if program[op.address..op.next()] == words[..] {
+        false
+    } else {
+        program[op.address..op.next()].copy_from_slice(&words);
+        true
+    }
+}
+
+fn immediate(raw: isize) -> Operand {
+    Operand {
+        mode: ParameterMode::Immediate,
+        raw,
+    }
+}
+
+fn fold(op: &Op) -> Option<Op> {
+    let value = match (op.opcode, op.constant(0), op.constant(1)) {
+        // An overflowing result is left for the CPU to compute
+        (1, Some(left), Some(right)) => left.checked_add(right)?,
+        (2, Some(left), Some(right)) => left.checked_mul(right)?,
+        (7, Some(left), Some(right)) => (left < right) as isize,
+        (8, Some(left), Some(right)) => (left == right) as isize,
+        (5, Some(condition), _) | (6, Some(condition), _) => {
+            let taken = (op.opcode == 5) == (condition != 0);
+            let target = if taken { op.operands[1] } else { immediate(0) };
+            return Some(Op {
+                address: op.address,
+                opcode: 5,
+                operands: vec![immediate(taken as isize), target],
+            });
+        }
+        _ => return None,
+    };
+    Some(Op {
+        address: op.address,
+        opcode: 1,
+        operands: vec![immediate(value), immediate(0), op.operands[2]],
+    })
+}
+
+/// The final target of a chain of unconditional jumps starting at `target`
+fn thread(analysis: &Analysis, start: usize) -> usize {
+    let mut target = start;
+    let mut seen = HashSet::new();
+    while seen.insert(target) {
+        match analysis.code.get(&target) {
+            Some(op)
+                if (op.opcode == 5 || op.opcode == 6)
+                    && !analysis.is_data(op)
+                    && op.constant(0).map(|value| (op.opcode == 5) == (value != 0))
+                        == Some(true) =>
+            {
+                match op.constant(1) {
+                    Some(next) if next >= 0 => target = next as usize,
+                    _ => break,
+                }
+            }
+            _ => break,
+        }
+    }
+    target
+}
+
+pub fn optimize(program: &[isize]) -> Optimization {
+    let mut result = Optimization {
+        program: program.to_vec(),
+        folded: 0,
+        inlined: 0,
+        threaded: 0,
+        dropped: 0,
+    };
+
+    loop {
+        let analysis = Analysis::new(&result.program);
+        if analysis.rewrites_code {
+            break;
+        }
+        let mut changed = false;
+
+        for op in analysis.code.values() {
+            if analysis.is_data(op) {
+                continue;
+            }
+            let mut rewritten = op.clone();
+
+            if !analysis.dynamic {
+                let write = op.write_operand();
+                let jump_target = if op.opcode == 5 || op.opcode == 6 {
+                    Some(1)
+                } else {
+                    None
+                };
+                for (index, operand) in rewritten.operands.iter_mut().enumerate() {
+                    if operand.mode == ParameterMode::Position
+                        && Some(index) != write
+                        && Some(index) != jump_target
+                        && operand.raw >= 0
+                        && !analysis.written.contains(&(operand.raw as usize))
+                    {
+                        if let Some(value) = result.program.get(operand.raw as usize) {
+                            *operand = immediate(*value);
+                            result.inlined += 1;
+                        }
+                    }
+                }
+            }
+
+            if let Some(folded) = fold(&rewritten) {
+                if folded.encode() != rewritten.encode() {
+                    result.folded += 1;
+                    rewritten = folded;
+                }
+            }
+
+            if rewritten.opcode == 5 || rewritten.opcode == 6 {
+                if let Some(target) = rewritten.constant(1).filter(|target| *target >= 0) {
+                    let threaded = thread(&analysis, target as usize);
+                    if threaded != target as usize && threaded != op.address {
+                        rewritten.operands[1] = immediate(threaded as isize);
+                        result.threaded += 1;
+                    }
+                }
+            }
+
+            changed |= store(&mut result.program, &rewritten);
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let analysis = Analysis::new(&result.program);
+    if !analysis.dynamic {
+        let end = analysis.code.values().map(Op::next).max().unwrap_or(0);
+        let live: HashSet<usize> = analysis
+            .code
+            .values()
+            .flat_map(|op| op.address..op.next())
+            .collect();
+        for cell in 0..end {
+            if !live.contains(&cell) && !analysis.data.contains(&cell) && result.program[cell] != 0
+            {
+                result.program[cell] = 0;
+                result.dropped += 1;
+            }
+        }
+    }
+
+    result
+}
+
+/// How a single run of a program ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: Vec<isize>,
+    pub result: CpuResult<ExitReason>,
+}
+
+/// Limits applied to every run of the equivalence check
+#[derive(Debug, Clone, Copy)]
+pub struct CheckConfig {
+    pub memory_size: usize,
+    pub step_limit: usize,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        CheckConfig {
+            memory_size: 4096,
+            step_limit: 1_000_000,
+        }
+    }
+}
+
+/// An input sequence the two programs disagree on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub input: Vec<isize>,
+    pub original: Outcome,
+    pub optimized: Outcome,
+}
+
+pub fn run_bounded(program: &[isize], input: &[isize], config: &CheckConfig) -> Outcome {
+    let mut cpu = CPU::from_memory(program.to_vec());
+    if cpu.memory.len() < config.memory_size {
+        cpu.set_memory_size(config.memory_size);
+    }
+    cpu.set_step_limit(config.step_limit);
+    let input = input.iter().join("\n");
+    let result = cpu.run(Some(&input));
+    Outcome {
+        output: cpu.get_output(),
+        result,
+    }
+}
+
+/// Run both programs on every input sequence and report the first one where their outputs or
+/// the way they stopped differ. An input both programs hit the step limit on only counts when
+/// their outputs so far already disagree.
+pub fn check_equivalence(
+    original: &[isize],
+    optimized: &[isize],
+    inputs: &[Vec<isize>],
+    config: &CheckConfig,
+) -> Result<(), Mismatch> {
+    for input in inputs {
+        let expected = run_bounded(original, input, config);
+        let actual = run_bounded(optimized, input, config);
+        let inconclusive = [&expected, &actual]
+            .iter()
+            .all(|outcome| matches!(outcome.result, Err(CpuError::StepLimitReached(_))))
+            && (expected.output.starts_with(&actual.output)
+                || actual.output.starts_with(&expected.output));
+        if expected != actual && !inconclusive {
+            return Err(Mismatch {
+                input: input.clone(),
+                original: expected,
+                optimized: actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// `count` pseudo-random input sequences of `length` values drawn from `low..=high`. The same
+/// seed always gives the same sequences.
+pub fn generate_inputs(
+    count: usize,
+    length: usize,
+    low: isize,
+    high: isize,
+    seed: u64,
+) -> Vec<Vec<isize>> {
+    let span = (high - low + 1).max(1) as u64;
+    let mut state = seed;
+    let mut next = move || {
+        // 64 bit linear congruential generator, high bits are the well mixed ones
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        low + ((state >> 33) % span) as isize
+    };
+    (0..count)
+        .map(|_| (0..length).map(|_| next()).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode::loader::parse_program;
+
+    #[test]
+    fn test_fold_constants() {
+        // mem[9] = 2 * 3, output mem[9]
+        let program = parse_program("1102,2,3,9,4,9,99,0,0,0").unwrap();
+        let optimized = optimize(&program);
+        assert_eq!(&optimized.program[0..4], &[1101, 6, 0, 9]);
+        assert_eq!(optimized.folded, 1);
+    }
+
+    #[test]
+    fn test_fold_overflow() {
+        // mem[9] = isize::MAX + 1 overflows, so it stays for the CPU to compute
+        let program = vec![1101, isize::MAX, 1, 9, 4, 9, 99, 0, 0, 0];
+        let optimized = optimize(&program);
+        assert_eq!(optimized.program, program);
+        assert_eq!(optimized.folded, 0);
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        // Jump to 3, which jumps to 6, which outputs 7
+        let program = parse_program("1105,1,3,1105,1,6,104,7,99").unwrap();
+        let optimized = optimize(&program);
+        assert_eq!(&optimized.program[0..3], &[1105, 1, 6]);
+        assert_eq!(optimized.threaded, 1);
+        // The middle jump is now dead
+        assert_eq!(&optimized.program[3..6], &[0, 0, 0]);
+        assert_eq!(optimized.dropped, 3);
+    }
+
+    #[test]
+    fn test_inline_read_only() {
+        // Output 10 + mem[9] where mem[9] is never written
+        let program = parse_program("1001,9,10,10,4,10,99,0,0,32,0").unwrap();
+        let optimized = optimize(&program);
+        assert_eq!(&optimized.program[0..4], &[1101, 42, 0, 10]);
+        assert_eq!(optimized.inlined, 1);
+    }
+
+    #[test]
+    fn test_self_modifying_is_left_alone() {
+        // The first instruction rewrites an operand of the foldable multiply at address 4
+        let program = parse_program("1101,5,0,5,1102,2,3,12,4,12,99,0,0").unwrap();
+        assert_eq!(optimize(&program).program, program);
+        let outcome = run_bounded(&program, &[], &CheckConfig::default());
+        assert_eq!(outcome.output, vec![15]);
+    }
+
+    #[test]
+    fn test_relative_write_is_left_alone() {
+        // A relative write to mem[3 + 4] rewrites an operand of the multiply at address 6
+        let program = parse_program("109,3,21101,0,7,4,1102,2,3,15,4,15,99,0,0,0").unwrap();
+        let optimized = optimize(&program);
+        assert_eq!(optimized.program, program);
+        assert_eq!(optimized.folded, 0);
+        let outcome = run_bounded(&program, &[], &CheckConfig::default());
+        assert_eq!(outcome.output, vec![21]);
+    }
+
+    #[test]
+    fn test_puzzle_programs_stay_equivalent() {
+        let config = CheckConfig::default();
+        let mut changed = 0;
+        for source in &[
+            include_str!("../../input/2019/day5.txt"),
+            include_str!("../../input/2019/day9.txt"),
+            include_str!("../../input/2019/day11.txt"),
+        ] {
+            let program = parse_program(source).unwrap();
+            let optimized = optimize(&program);
+            if optimized.program != program {
+                changed += 1;
+            }
+            // Still loadable as text
+            assert_eq!(
+                parse_program(&optimized.to_source()),
+                Ok(optimized.program.clone())
+            );
+            let inputs = generate_inputs(8, 1, 0, 9, 2019);
+            assert_eq!(
+                check_equivalence(&program, &optimized.program, &inputs, &config),
+                Ok(())
+            );
+        }
+        // Day 9 keeps its relative writes on the stack, so at least it gets folded
+        assert!(changed > 0);
+    }
+
+    #[test]
+    fn test_mismatch_reported() {
+        let original = parse_program("3,0,4,0,99").unwrap();
+        let changed = parse_program("3,0,104,0,99").unwrap();
+        let inputs = vec![vec![0], vec![5]];
+        let mismatch = check_equivalence(&original, &changed, &inputs, &CheckConfig::default())
+            .expect_err("Outputs differ for a non-zero input");
+        assert_eq!(mismatch.input, vec![5]);
+        assert_eq!(mismatch.original.output, vec![5]);
+        assert_eq!(mismatch.optimized.output, vec![0]);
+    }
+
+    #[test]
+    fn test_step_limit_inconclusive() {
+        // Both loop forever printing 1, the second one slower
+        let original = parse_program("104,1,1105,1,0").unwrap();
+        let slower = parse_program("104,1,1101,0,0,9,1105,1,0,0").unwrap();
+        let config = CheckConfig {
+            memory_size: 16,
+            step_limit: 100,
+        };
+        let inputs = vec![vec![]];
+        assert_eq!(
+            check_equivalence(&original, &slower, &inputs, &config),
+            Ok(())
+        );
+
+        // Outputs that already disagree are still a mismatch
+        let other = parse_program("104,2,1105,1,0").unwrap();
+        assert!(check_equivalence(&original, &other, &inputs, &config).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Memory protection for intcode programs. Address ranges can be marked read-only,
+//! execute-only or no-execute, and every instruction is checked against them before it
+//! runs, so a program that would write into its own code or jump into its data faults with
+//! the address and the IP responsible instead of silently corrupting its state.
+//!
+//! Only what the program itself does is checked. `set_memory` and opcode extension handlers
+//! act for the host and can still patch protected memory.
+
+use std::ops::Range;
+
+use super::decode::ParameterMode;
+use super::{CpuError, CpuResult, CPU};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protection {
+    /// Can be read and executed but not written, for code that reads its own constants
+    ReadOnly,
+    /// Can only be executed
+    ExecuteOnly,
+    /// Can be read and written but never executed
+    NoExecute,
+}
+
+impl Protection {
+    fn can_read(self) -> bool {
+        self != Protection::ExecuteOnly
+    }
+
+    fn can_write(self) -> bool {
+        self == Protection::NoExecute
+    }
+
+    fn can_execute(self) -> bool {
+        self != Protection::NoExecute
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryMap {
+    regions: Vec<(Range<usize>, Protection)>,
+    /// The last instruction that passed the checks, blamed when execution moves on into
+    /// memory it may not execute
+    last_ip: Option<usize>,
+}
+
+impl MemoryMap {
+    /// The protection of `address`, the region added last wins where they overlap
+    fn protection(&self, address: usize) -> Option<Protection> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(_, protection)| *protection)
+    }
+
+    fn allows(&self, address: usize, check: fn(Protection) -> bool) -> bool {
+        match self.protection(address) {
+            Some(protection) => check(protection),
+            None => true,
+        }
+    }
+}
+
+impl CPU {
+    /// Protect `range` from the program. Later calls take precedence where ranges overlap.
+    pub fn protect(&mut self, range: Range<usize>, protection: Protection) {
+        self.memory_map
+            .get_or_insert_with(MemoryMap::default)
+            .regions
+            .push((range, protection));
+    }
+
+    pub fn clear_protection(&mut self) {
+        self.memory_map = None;
+    }
+
+    /// Fail if the instruction at the instruction pointer would break a protected region
+    pub(crate) fn check_protection(&mut self) -> CpuResult<()> {
+        let ip = self.instruction_pointer;
+        let map = match self.memory_map.as_mut() {
+            Some(map) => map,
+            None => return Ok(()),
+        };
+        let op = self.memory.decode(ip);
+        let mut words = match &op {
+            Some(op) => ip..op.next(),
+            None => ip..ip + 1,
+        };
+        if let Some(address) = words.find(|address| !map.allows(*address, Protection::can_execute))
+        {
+            // Falling or jumping into the region is the fault of the instruction before
+            let culprit = if address == ip {
+                map.last_ip.unwrap_or(ip)
+            } else {
+                ip
+            };
+            return Err(CpuError::ExecuteViolation(address, culprit));
+        }
+
+        if let Some(op) = op {
+            let write_operand = op.write_operand();
+            for (index, operand) in op.operands.iter().enumerate() {
+                let address = match operand.mode {
+                    ParameterMode::Position => operand.raw,
+                    ParameterMode::Relative => self.relative_base + operand.raw,
+                    ParameterMode::Immediate => continue,
+                };
+                if address < 0 {
+                    continue;
+                }
+                let address = address as usize;
+                if write_operand == Some(index) {
+                    if !map.allows(address, Protection::can_write) {
+                        return Err(CpuError::WriteViolation(address, ip));
+                    }
+                } else if !map.allows(address, Protection::can_read) {
+                    return Err(CpuError::ReadViolation(address, ip));
+                }
+            }
+        }
+        map.last_ip = Some(ip);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ExitReason;
+    use super::*;
+
+    #[test]
+    fn test_self_modification() {
+        // Overwrite the halt at 4 with the add at 0
+        let mut cpu = CPU::new("1,0,9,4,99,0,0,0,0,0");
+        cpu.protect(0..5, Protection::ReadOnly);
+        assert_eq!(cpu.run(None), Err(CpuError::WriteViolation(4, 0)));
+
+        // Reading code is fine as long as it is not execute only
+        let mut cpu = CPU::new("1,0,9,8,99,0,0,0,0,0");
+        cpu.protect(0..5, Protection::ReadOnly);
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        cpu.protect(0..5, Protection::ExecuteOnly);
+        cpu.instruction_pointer = 0;
+        assert_eq!(cpu.run(None), Err(CpuError::ReadViolation(0, 0)));
+    }
+
+    #[test]
+    fn test_jump_into_data() {
+        // Jump to the data at 6, which holds a valid halt
+        let mut cpu = CPU::new("1105,1,6,99,0,0,99");
+        cpu.protect(4..7, Protection::NoExecute);
+        assert_eq!(cpu.run(None), Err(CpuError::ExecuteViolation(6, 0)));
+        cpu.clear_protection();
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+    }
+
+    #[test]
+    fn test_data_writes() {
+        // Write the input to 5 then halt, with everything but the data cells execute only
+        let mut cpu = CPU::new("3,5,4,5,99,0");
+        cpu.protect(0..5, Protection::ExecuteOnly);
+        cpu.protect(5..6, Protection::NoExecute);
+        assert_eq!(cpu.run(Some("8")), Ok(ExitReason::Halt));
+        assert_eq!(cpu.get_output(), vec![8]);
+    }
+}
@@ -0,0 +1,422 @@
+//! Expect-style scripts for driving interactive programs, one command per line:
+//!
+//! ```text
+//! # Comments run to the end of the line
+//! expect text 'Command?'     # read outputs as ASCII until they end with this text
+//! send line 'north'          # the characters and a newline
+//! send text 'n'              # just the characters
+//! send 3                     # one or more comma separated values
+//! expect output 1,0,4        # exactly these next output values
+//! expect input               # the program is waiting for input nothing was sent for
+//! expect halt                # the program halts
+//! ```
+//!
+//! Sent values are queued and handed to the program as it asks for them, so a `send` can
+//! come before the prompt it answers. Strings are in single or double quotes and understand
+//! `\n`, `\t`, `\\` and escaped quotes.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::{ExitReason, CPU};
+
+/// How many of the latest outputs a mismatch shows
+const CONTEXT: usize = 40;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Send(Vec<isize>),
+    ExpectOutput(Vec<isize>),
+    ExpectText(String),
+    ExpectInput,
+    ExpectHalt,
+}
+
+/// Where a script failed, either parsing or running
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptError {
+    /// 1-based line of the command
+    pub line: usize,
+    pub command: String,
+    pub message: String,
+    /// The last outputs before the failure, oldest first
+    pub recent: Vec<isize>,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {} `{}` : {}",
+            self.line, self.command, self.message
+        )?;
+        if !self.recent.is_empty() {
+            let values: Vec<String> = self.recent.iter().map(|value| value.to_string()).collect();
+            write!(f, "\n  last outputs : {}", values.join(","))?;
+            if let Some(text) = ascii(&self.recent) {
+                write!(f, "\n  as text : {:?}", text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// The values as text, if they are all printable ASCII or whitespace
+fn ascii(values: &[isize]) -> Option<String> {
+    values
+        .iter()
+        .map(|value| match *value {
+            9 | 10 | 13 | 32..=126 => Some(*value as u8 as char),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    /// Each command with its line number and source
+    commands: Vec<(usize, String, Command)>,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script, ScriptError> {
+        let mut commands = vec![];
+        for (index, line) in source.lines().enumerate() {
+            let text = strip_comment(line).trim();
+            if text.is_empty() {
+                continue;
+            }
+            let command = parse_command(text).map_err(|message| ScriptError {
+                line: index + 1,
+                command: text.to_string(),
+                message,
+                recent: vec![],
+            })?;
+            commands.push((index + 1, text.to_string(), command));
+        }
+        Ok(Script { commands })
+    }
+
+    /// Run the script against `cpu`, stopping at the first command that does not hold
+    pub fn run(&self, cpu: &mut CPU) -> Result<(), ScriptError> {
+        let mut session = Session::new(cpu);
+        for (line, text, command) in &self.commands {
+            session.execute(command).map_err(|message| ScriptError {
+                line: *line,
+                command: text.clone(),
+                message,
+                recent: session.recent.iter().cloned().collect(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything before a `#` that is not inside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(open), c) if c == open => quote = None,
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '#') => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_command(text: &str) -> Result<Command, String> {
+    let (verb, rest) = split_word(text);
+    let (kind, argument) = split_word(rest);
+    match (verb, kind) {
+        ("send", "line") => Ok(Command::Send(
+            format!("{}\n", parse_string(argument)?)
+                .bytes()
+                .map(isize::from)
+                .collect(),
+        )),
+        ("send", "text") => Ok(Command::Send(
+            parse_string(argument)?.bytes().map(isize::from).collect(),
+        )),
+        ("send", _) => Ok(Command::Send(parse_values(rest)?)),
+        ("expect", "output") => Ok(Command::ExpectOutput(parse_values(argument)?)),
+        ("expect", "text") => Ok(Command::ExpectText(parse_string(argument)?)),
+        ("expect", "input") if argument.is_empty() => Ok(Command::ExpectInput),
+        ("expect", "halt") if argument.is_empty() => Ok(Command::ExpectHalt),
+        _ => Err("Unknown command".to_string()),
+    }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim()),
+        None => (text, ""),
+    }
+}
+
+fn parse_values(text: &str) -> Result<Vec<isize>, String> {
+    if text.is_empty() {
+        return Err("Expected values".to_string());
+    }
+    text.split(',')
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid value `{}`", value.trim()))
+        })
+        .collect()
+}
+
+fn parse_string(text: &str) -> Result<String, String> {
+    let mut chars = text.chars();
+    let quote = match chars.next() {
+        Some(c @ '\'') | Some(c @ '"') => c,
+        _ => return Err("Expected a quoted string".to_string()),
+    };
+    let mut string = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => string.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => c,
+                other => return Err(format!("Unknown escape `\\{}`", other.unwrap_or(' '))),
+            }),
+            c if c == quote => {
+                return if chars.as_str().trim().is_empty() {
+                    Ok(string)
+                } else {
+                    Err("Unexpected text after the string".to_string())
+                };
+            }
+            c => string.push(c),
+        }
+    }
+    Err("Unterminated string".to_string())
+}
+
+/// Where the program stopped when it had no output left to give
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    Input,
+    Halt,
+}
+
+struct Session<'a> {
+    cpu: &'a mut CPU,
+    input: VecDeque<isize>,
+    recent: VecDeque<isize>,
+    waiting: bool,
+    halted: bool,
+}
+
+impl<'a> Session<'a> {
+    fn new(cpu: &'a mut CPU) -> Session<'a> {
+        cpu.set_exit_on_output();
+        Session {
+            cpu,
+            input: VecDeque::new(),
+            recent: VecDeque::new(),
+            waiting: false,
+            halted: false,
+        }
+    }
+
+    /// Run until the next output, or report why there is none
+    fn next_output(&mut self) -> Result<Result<isize, Stop>, String> {
+        loop {
+            if self.halted {
+                return Ok(Err(Stop::Halt));
+            }
+            let value = if self.waiting {
+                match self.input.pop_front() {
+                    Some(value) => Some(value.to_string()),
+                    None => return Ok(Err(Stop::Input)),
+                }
+            } else {
+                None
+            };
+            // One value per run, so nothing queued is lost when the program outputs first
+            match self.cpu.run(value.as_deref()) {
+                Ok(ExitReason::OutputGenerated) => {
+                    self.waiting = false;
+                    let output = *self.cpu.get_last_output().expect("An output was generated");
+                    if self.recent.len() == CONTEXT {
+                        self.recent.pop_front();
+                    }
+                    self.recent.push_back(output);
+                    return Ok(Ok(output));
+                }
+                Ok(ExitReason::InputRequired) => self.waiting = true,
+                Ok(ExitReason::Halt) => self.halted = true,
+                Err(error) => return Err(format!("CPU fault {:?}", error)),
+            }
+        }
+    }
+
+    fn execute(&mut self, command: &Command) -> Result<(), String> {
+        match command {
+            Command::Send(values) => self.input.extend(values),
+            Command::ExpectOutput(expected) => {
+                let mut found = vec![];
+                for value in expected {
+                    match self.next_output()? {
+                        Ok(output) => found.push(output),
+                        Err(stop) => {
+                            return Err(format!(
+                                "Expected {:?} but got {:?} before {}",
+                                expected,
+                                found,
+                                describe(stop)
+                            ))
+                        }
+                    }
+                    if found.last() != Some(value) {
+                        return Err(format!("Expected {:?} but got {:?}", expected, found));
+                    }
+                }
+            }
+            Command::ExpectText(expected) => {
+                let mut found = String::new();
+                while !found.ends_with(expected.as_str()) {
+                    match self.next_output()? {
+                        Ok(output) if (0..128).contains(&output) => {
+                            found.push(output as u8 as char)
+                        }
+                        Ok(output) => {
+                            return Err(format!(
+                                "Expected text {:?} but got the value {} after {:?}",
+                                expected, output, found
+                            ))
+                        }
+                        Err(stop) => {
+                            return Err(format!(
+                                "Expected text {:?} but got {:?} before {}",
+                                expected,
+                                found,
+                                describe(stop)
+                            ))
+                        }
+                    }
+                }
+            }
+            Command::ExpectInput | Command::ExpectHalt => {
+                let wanted = match command {
+                    Command::ExpectInput => Stop::Input,
+                    _ => Stop::Halt,
+                };
+                match self.next_output()? {
+                    Err(stop) if stop == wanted => {}
+                    Err(stop) => {
+                        return Err(format!(
+                            "Expected {} but got {}",
+                            describe(wanted),
+                            describe(stop)
+                        ))
+                    }
+                    Ok(output) => {
+                        return Err(format!(
+                            "Expected {} but got the output {}",
+                            describe(wanted),
+                            output
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn describe(stop: Stop) -> &'static str {
+    match stop {
+        Stop::Input => "a request for input",
+        Stop::Halt => "a halt",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Print `?`, then echo every character read
+    const ECHO: &str = "104,63,3,100,4,100,1105,1,2";
+
+    fn echo() -> CPU {
+        let mut cpu = CPU::new(ECHO);
+        cpu.set_memory_size(101);
+        cpu
+    }
+
+    #[test]
+    fn test_puzzle_sessions() {
+        let day5 = Script::parse("send 5\nexpect output 5000972\nexpect halt").unwrap();
+        day5.run(&mut CPU::new(include_str!("../../input/2019/day5.txt")))
+            .unwrap();
+
+        let day9 = Script::parse(
+            "# BOOST keycode\nsend 1\nexpect output 2662308295 # keycode\nexpect halt",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(include_str!("../../input/2019/day9.txt"));
+        cpu.set_memory_size(4096);
+        day9.run(&mut cpu).unwrap();
+    }
+
+    #[test]
+    fn test_ascii_session() {
+        let script = Script::parse(
+            r#"
+            expect text '?'
+            expect input
+            send line 'north # not a comment'
+            expect text "north # not"
+            expect text ' a comment\n'
+            send text 'it\'s'
+            expect output 105,116,39,115
+            expect input
+            "#,
+        )
+        .unwrap();
+        script.run(&mut echo()).unwrap();
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let script = Script::parse("send line 'ab'\nexpect text '?'\nexpect output 97,99").unwrap();
+        let error = script.run(&mut echo()).unwrap_err();
+        assert_eq!(error.line, 3);
+        assert_eq!(error.command, "expect output 97,99");
+        assert_eq!(error.recent, vec![63, 97, 98]);
+        assert!(error.to_string().contains("as text : \"?ab\""));
+
+        let error = Script::parse("expect halt")
+            .unwrap()
+            .run(&mut echo())
+            .unwrap_err();
+        assert_eq!(error.message, "Expected a halt but got the output 63");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (source, message) in [
+            ("send", "Expected values"),
+            ("send 1,x", "Invalid value `x`"),
+            ("send line north", "Expected a quoted string"),
+            ("expect text 'open", "Unterminated string"),
+            ("expect halt now", "Unknown command"),
+            ("jump 4", "Unknown command"),
+        ] {
+            let error = Script::parse(&format!("\n{}", source)).unwrap_err();
+            assert_eq!((error.line, error.message.as_str()), (2, message));
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::iter;
+
+use itertools::{Either, Itertools};
+use rayon::prelude::*;
+
+use super::{ExitReason, CPU};
+
+/// A single point in the search space: memory patches applied before the run and the
+/// input sequence fed to the program.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Candidate {
+    pub patches: Vec<(usize, isize)>,
+    pub input: Vec<isize>,
+}
+
+/// A candidate that satisfied the predicate, along with the CPU state it halted in
+#[derive(Debug, Clone)]
+pub struct SearchMatch {
+    pub candidate: Candidate,
+    pub cpu: CPU,
+}
+
+/// Parallel search over memory patches and input sequences for a base `CPU`.
+///
+/// Every patch axis and the input axis are combined as a cartesian product, each candidate
+/// runs on its own clone of the base CPU until it halts, and the predicate is evaluated
+/// against the halted CPU. Candidates that fault, patch outside memory, run out of input or
+/// run past the step limit never match.
+#[derive(Debug, Clone)]
+pub struct Search {
+    base: CPU,
+    patches: Vec<(usize, Vec<isize>)>,
+    inputs: Vec<Vec<isize>>,
+    step_limit: usize,
+}
+
+/// Instructions a candidate may execute unless `Search::step_limit` says otherwise
+pub const DEFAULT_STEP_LIMIT: usize = 1_000_000;
+
+/// Candidates handed to the thread pool at a time, so the search space is never held in
+/// memory as a whole
+const CHUNK_SIZE: usize = 4096;
+
+impl Search {
+    pub fn new(base: &CPU) -> Search {
+        let mut base = base.clone();
+        base.clear_exit_on_output();
+        Search {
+            base,
+            patches: vec![],
+            inputs: vec![],
+            step_limit: DEFAULT_STEP_LIMIT,
+        }
+    }
+
+    /// Give up on a candidate after it executed `limit` instructions
+    pub fn step_limit(mut self, limit: usize) -> Search {
+        self.step_limit = limit;
+        self
+    }
+
+    /// Try every value in `values` at `address`
+    pub fn patch<I: IntoIterator<Item = isize>>(mut self, address: usize, values: I) -> Search {
+        self.patches.push((address, values.into_iter().collect()));
+        self
+    }
+
+    /// Try each of the given input sequences
+    pub fn inputs<I: IntoIterator<Item = Vec<isize>>>(mut self, inputs: I) -> Search {
+        self.inputs.extend(inputs);
+        self
+    }
+
+    /// Try every ordering of `values` as the input sequence
+    pub fn input_permutations(self, values: &[isize]) -> Search {
+        let count = values.len();
+        self.inputs(values.iter().cloned().permutations(count))
+    }
+
+    /// Every candidate in the search space, in the order `find_first` will report them.
+    /// They are generated as the iterator advances.
+    pub fn candidates(&self) -> impl Iterator<Item = Candidate> + '_ {
+        let patch_sets = if self.patches.is_empty() {
+            Either::Left(iter::once(vec![]))
+        } else {
+            Either::Right(
+                self.patches
+                    .iter()
+                    .map(|(address, values)| values.iter().map(move |value| (*address, *value)))
+                    .multi_cartesian_product(),
+            )
+        };
+        let input_count = self.inputs.len().max(1);
+        patch_sets.flat_map(move |patches| {
+            (0..input_count).map(move |index| Candidate {
+                patches: patches.clone(),
+                input: self.inputs.get(index).cloned().unwrap_or_default(),
+            })
+        })
+    }
+
+    /// The candidates in search space order, `CHUNK_SIZE` at a time
+    fn chunks(&self) -> impl Iterator<Item = Vec<Candidate>> + '_ {
+        let mut candidates = self.candidates();
+        iter::from_fn(move || {
+            let chunk: Vec<Candidate> = candidates.by_ref().take(CHUNK_SIZE).collect();
+            if chunk.is_empty() {
+                None
+            } else {
+                Some(chunk)
+            }
+        })
+    }
+
+    /// A clone of the base CPU with the patches of `candidate` applied and the step limit
+    /// set, or None if a patch is outside memory
+    pub fn prepare(&self, candidate: &Candidate) -> Option<CPU> {
+        let mut cpu = self.base.clone();
+        for (address, value) in &candidate.patches {
+            if *address >= cpu.memory().len() {
+                return None;
+            }
+            cpu.set_memory(*address, *value);
+        }
+        cpu.set_step_limit(cpu.get_steps() + self.step_limit);
+        Some(cpu)
+    }
+
+    /// Run a single candidate to completion, returning the CPU only if it halted cleanly
+    pub fn run_candidate(&self, candidate: &Candidate) -> Option<CPU> {
+        let mut cpu = self.prepare(candidate)?;
+        let input = candidate.input.iter().join("\n");
+        match cpu.run(Some(&input)) {
+            Ok(ExitReason::Halt) => Some(cpu),
+            _ => None,
+        }
+    }
+
+    /// The first candidate, in search space order, that satisfies `predicate`. Candidates
+    /// later in the order are abandoned once a match is found.
+    pub fn find_first<F>(&self, predicate: F) -> Option<SearchMatch>
+    where
+        F: Fn(&CPU) -> bool + Sync,
+    {
+        self.chunks().find_map(|chunk| {
+            chunk
+                .into_par_iter()
+                .filter_map(|candidate| self.evaluate(candidate, &predicate))
+                .find_first(|_| true)
+        })
+    }
+
+    /// Every candidate that satisfies `predicate`, in search space order
+    pub fn find_all<F>(&self, predicate: F) -> Vec<SearchMatch>
+    where
+        F: Fn(&CPU) -> bool + Sync,
+    {
+        self.chunks()
+            .flat_map(|chunk| {
+                chunk
+                    .into_par_iter()
+                    .filter_map(|candidate| self.evaluate(candidate, &predicate))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// The halted candidate with the largest score
+    pub fn max_by_key<F>(&self, score: F) -> Option<SearchMatch>
+    where
+        F: Fn(&CPU) -> isize + Sync,
+    {
+        self.chunks()
+            .filter_map(|chunk| {
+                chunk
+                    .into_par_iter()
+                    .filter_map(|candidate| self.evaluate(candidate, &|_: &CPU| true))
+                    .map(|found| (score(&found.cpu), found))
+                    .max_by_key(|(value, _)| *value)
+            })
+            .max_by_key(|(value, _)| *value)
+            .map(|(_, found)| found)
+    }
+
+    /// The candidate with the largest score, for candidates that take more than a single
+    /// run to judge, like a chain of CPUs. `score` gets the prepared CPU to clone or run and
+    /// returns None for a candidate that failed.
+    pub fn max_by_score<F>(&self, score: F) -> Option<(Candidate, isize)>
+    where
+        F: Fn(CPU, &Candidate) -> Option<isize> + Sync,
+    {
+        self.chunks()
+            .filter_map(|chunk| {
+                chunk
+                    .into_par_iter()
+                    .filter_map(|candidate| {
+                        let value = score(self.prepare(&candidate)?, &candidate)?;
+                        Some((candidate, value))
+                    })
+                    .max_by_key(|(_, value)| *value)
+            })
+            .max_by_key(|(_, value)| *value)
+    }
+
+    fn evaluate<F>(&self, candidate: Candidate, predicate: &F) -> Option<SearchMatch>
+    where
+        F: Fn(&CPU) -> bool,
+    {
+        let cpu = self.run_candidate(&candidate)?;
+        if predicate(&cpu) {
+            Some(SearchMatch { candidate, cpu })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_search_space() {
+        // A billion candidates, the very first of which matches
+        let cpu = CPU::new("1,0,0,0,99");
+        let found = Search::new(&cpu)
+            .patch(1, 0..1000)
+            .patch(2, 0..1000)
+            .patch(3, 0..1000)
+            .find_first(|cpu| cpu.get_memory(0) == 2)
+            .expect("Expected a matching patch");
+        assert_eq!(found.candidate.patches, vec![(1, 0), (2, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn test_patch_search() {
+        let cpu = CPU::new("1,0,0,0,99");
+        let found = Search::new(&cpu)
+            .patch(1, 0..5)
+            .patch(2, 0..5)
+            .find_first(|cpu| cpu.get_memory(0) == 100)
+            .expect("Expected a matching patch");
+        assert_eq!(found.candidate.patches, vec![(1, 0), (2, 4)]);
+    }
+
+    #[test]
+    fn test_find_all() {
+        let cpu = CPU::new("1,0,0,0,99");
+        let found = Search::new(&cpu)
+            .patch(1, 0..5)
+            .patch(2, 0..5)
+            .find_all(|cpu| cpu.get_memory(0) == 2);
+        assert!(found.iter().all(|m| m.cpu.get_memory(0) == 2));
+        assert_eq!(found[0].candidate.patches, vec![(1, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn test_input_permutations() {
+        // Outputs input[0] * 10 + input[1]
+        let cpu = CPU::new("3,15,3,16,1002,15,10,15,1,15,16,15,4,15,99,0,0");
+        let best = Search::new(&cpu)
+            .input_permutations(&[1, 2, 3])
+            .max_by_key(|cpu| *cpu.get_last_output().unwrap_or(&0))
+            .expect("Expected a halted candidate");
+        assert_eq!(best.candidate.input, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_starved_candidates_never_match() {
+        let cpu = CPU::new("3,0,99");
+        assert!(Search::new(&cpu).find_first(|_| true).is_none());
+    }
+
+    #[test]
+    fn test_failing_candidates_never_match() {
+        // Read from the address patched into 1, then loop forever if it was 0
+        let cpu = CPU::new("4,5,1006,1,2,99");
+        let search = Search::new(&cpu).patch(1, vec![500, 0, 5]).step_limit(100);
+        let found = search.find_all(|_| true);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].candidate.patches, vec![(1, 5)]);
+        assert!(Search::new(&cpu)
+            .patch(10, 0..2)
+            .find_first(|_| true)
+            .is_none());
+    }
+
+    #[test]
+    fn test_max_by_score() {
+        // Two runs chained, the second taking the output of the first
+        let cpu = CPU::new("3,15,3,16,1002,15,10,15,1,15,16,15,4,15,99,0,0");
+        let (best, thrust) = Search::new(&cpu)
+            .input_permutations(&[1, 2, 3])
+            .max_by_score(|cpu, candidate| {
+                let mut first = cpu.clone();
+                first
+                    .run(Some(&format!(
+                        "{}\n{}",
+                        candidate.input[0], candidate.input[1]
+                    )))
+                    .ok()?;
+                let mut second = cpu;
+                let input = format!("{}\n{}", first.get_last_output()?, candidate.input[2]);
+                second.run(Some(&input)).ok()?;
+                second.get_last_output().copied()
+            })
+            .expect("Expected a scored candidate");
+        assert_eq!(best.input, vec![3, 2, 1]);
+        assert_eq!(thrust, 321);
+    }
+}
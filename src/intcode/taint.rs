@@ -0,0 +1,369 @@
+//! Taint tracking from the values a program is given to the values it outputs. Every cell
+//! carries the set of sources its value depends on: input values, by the order they were
+//! read in, and cells the host patched with `set_memory`. Each instruction stores into its
+//! destination the sources of everything it used, which for an operand are the cell it was
+//! read from and the cells that gave its address: the instruction word, and the relative
+//! base in relative mode. An extension storing with `CPU::store` uses all its operands.
+//! Each output is recorded with its sources.
+//!
+//! Following values alone misses what a program does differently because of a branch. With
+//! `Dependence::Control` the sources of every jump condition and computed jump target are
+//! added to everything stored until the function that branched returns, using the shadow
+//! call stack of `intcode::calls`. Where the two sides of a branch join again is not known,
+//! so within a function that is an over-approximation.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::decode::{Op, ParameterMode};
+use super::extension::OperandKind;
+use super::CPU;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Source {
+    /// The nth value read from the input, from 0
+    Input(usize),
+    /// A cell the host set
+    Patch(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dependence {
+    /// Only what values are computed from
+    Data,
+    /// Values, and which way the jumps before them went
+    Control,
+}
+
+/// An output value and the sources it depends on
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaintedOutput {
+    pub value: isize,
+    pub sources: BTreeSet<Source>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TaintTracker {
+    dependence: Dependence,
+    /// Sources of every tainted cell, clean cells are left out
+    cells: HashMap<usize, BTreeSet<Source>>,
+    relative_base: BTreeSet<Source>,
+    inputs: usize,
+    /// Sources of the branches taken so far, with the call depth they were taken at
+    branches: Vec<(usize, BTreeSet<Source>)>,
+    outputs: Vec<TaintedOutput>,
+}
+
+impl TaintTracker {
+    fn new(dependence: Dependence) -> TaintTracker {
+        TaintTracker {
+            dependence,
+            cells: HashMap::new(),
+            relative_base: BTreeSet::new(),
+            inputs: 0,
+            branches: vec![],
+            outputs: vec![],
+        }
+    }
+
+    fn cell(&self, address: usize) -> impl Iterator<Item = &Source> {
+        self.cells.get(&address).into_iter().flatten()
+    }
+
+    fn store(&mut self, address: usize, mut sources: BTreeSet<Source>) {
+        for (_, branch) in &self.branches {
+            sources.extend(branch);
+        }
+        if sources.is_empty() {
+            self.cells.remove(&address);
+        } else {
+            self.cells.insert(address, sources);
+        }
+    }
+
+    /// Sources of the cells that locate operand `index` of `op`, and of the cell it refers to
+    /// if the instruction reads it
+    fn operand(&self, op: &Op, index: usize, relative_base: isize, read: bool) -> BTreeSet<Source> {
+        let mut sources: BTreeSet<Source> = self.cell(op.address + 1 + index).cloned().collect();
+        let operand = op.operands[index];
+        let address = match operand.mode {
+            ParameterMode::Immediate => return sources,
+            ParameterMode::Position => operand.raw,
+            ParameterMode::Relative => {
+                sources.extend(&self.relative_base);
+                relative_base + operand.raw
+            }
+        };
+        if read && address >= 0 {
+            sources.extend(self.cell(address as usize));
+        }
+        sources
+    }
+}
+
+impl CPU {
+    /// Track what every value depends on from now on. Inputs are counted from here, and
+    /// only patches made after this call are sources.
+    pub fn set_taint_tracking(&mut self, dependence: Dependence) {
+        if dependence == Dependence::Control && self.call_stack.is_none() {
+            self.set_call_tracking();
+        }
+        self.taint = Some(TaintTracker::new(dependence));
+    }
+
+    pub fn clear_taint_tracking(&mut self) {
+        self.taint = None;
+    }
+
+    /// The sources the value at `address` depends on
+    pub fn taint(&self, address: usize) -> BTreeSet<Source> {
+        self.taint
+            .as_ref()
+            .map(|tracker| tracker.cell(address).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Every output since taint tracking started, with its sources
+    pub fn tainted_outputs(&self) -> &[TaintedOutput] {
+        self.taint
+            .as_ref()
+            .map_or(&[], |tracker| tracker.outputs.as_slice())
+    }
+
+    pub(crate) fn taint_patch(&mut self, address: usize) {
+        if let Some(tracker) = self.taint.as_mut() {
+            tracker.store(address, Some(Source::Patch(address)).into_iter().collect());
+        }
+    }
+
+    /// Label the cell an input instruction just stored to
+    pub(crate) fn taint_input(&mut self, address: usize) {
+        if let Some(tracker) = self.taint.as_mut() {
+            let mut sources: BTreeSet<Source> =
+                Some(Source::Input(tracker.inputs)).into_iter().collect();
+            if let Some(op) = self.memory.decode(self.instruction_pointer) {
+                sources.extend(tracker.operand(&op, 0, self.relative_base, false));
+            }
+            tracker.inputs += 1;
+            tracker.store(address, sources);
+        }
+    }
+
+    /// Label a cell the extension at the instruction pointer stored to with the sources of
+    /// every operand it took
+    pub(crate) fn taint_extension_write(&mut self, address: usize) {
+        if self.taint.is_none() {
+            return;
+        }
+        let extension = self
+            .pending_extension()
+            .and_then(|(registry, opcode)| registry.get(opcode).cloned());
+        let tracker = match self.taint.as_mut() {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        let mut sources = BTreeSet::new();
+        if let Some(extension) = extension {
+            if let Some(op) = extension.decode(&self.memory, self.instruction_pointer) {
+                for (index, kind) in extension.operands.iter().enumerate() {
+                    let read = *kind == OperandKind::Read;
+                    sources.extend(tracker.operand(&op, index, self.relative_base, read));
+                }
+            }
+        }
+        tracker.store(address, sources);
+    }
+
+    /// Record an output the instruction at the instruction pointer produced
+    pub(crate) fn taint_output(&mut self, value: isize) {
+        if let Some(tracker) = self.taint.as_mut() {
+            let mut sources = BTreeSet::new();
+            if let Some(op) = self.memory.decode(self.instruction_pointer) {
+                sources.extend(tracker.operand(&op, 0, self.relative_base, true));
+            }
+            for (_, branch) in &tracker.branches {
+                sources.extend(branch);
+            }
+            tracker.outputs.push(TaintedOutput { value, sources });
+        }
+    }
+
+    /// Spread the sources of the instruction at the instruction pointer, which is about to
+    /// run. Inputs and outputs are handled once they actually happen.
+    pub(crate) fn propagate_taint(&mut self) {
+        let tracker = match self.taint.as_mut() {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        let op = match self.memory.decode(self.instruction_pointer) {
+            Some(op) => op,
+            None => return,
+        };
+        if let Some(calls) = &self.call_stack {
+            let depth = calls.depth();
+            tracker.branches.retain(|(at, _)| *at <= depth);
+        }
+        let relative_base = self.relative_base;
+        let used = |tracker: &TaintTracker, operands: std::ops::Range<usize>| {
+            operands
+                .flat_map(|index| {
+                    let read = op.write_operand() != Some(index);
+                    tracker.operand(&op, index, relative_base, read)
+                })
+                .collect::<BTreeSet<Source>>()
+        };
+
+        match op.opcode {
+            1 | 2 | 7 | 8 => {
+                let sources = used(tracker, 0..3);
+                let operand = op.operands[2];
+                let address = match operand.mode {
+                    ParameterMode::Relative => relative_base + operand.raw,
+                    _ => operand.raw,
+                };
+                if address >= 0 {
+                    tracker.store(address as usize, sources);
+                }
+            }
+            5 | 6 if tracker.dependence == Dependence::Control => {
+                let sources = used(tracker, 0..2);
+                if !sources.is_empty() {
+                    let depth = self.call_stack.as_ref().map_or(0, |calls| calls.depth());
+                    match tracker.branches.last_mut() {
+                        Some((at, branch)) if *at == depth => branch.extend(sources),
+                        _ => tracker.branches.push((depth, sources)),
+                    }
+                }
+            }
+            9 => {
+                let mut sources = used(tracker, 0..1);
+                sources.extend(&tracker.relative_base);
+                for (_, branch) in &tracker.branches {
+                    sources.extend(branch);
+                }
+                tracker.relative_base = sources;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::extension::{Flow, OpcodeRegistry};
+    use super::super::ExitReason;
+    use super::*;
+
+    fn sources(list: &[Source]) -> BTreeSet<Source> {
+        list.iter().cloned().collect()
+    }
+
+    fn outputs(cpu: &CPU) -> Vec<(isize, BTreeSet<Source>)> {
+        cpu.tainted_outputs()
+            .iter()
+            .map(|output| (output.value, output.sources.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_data() {
+        // Output the sum of two inputs, then a constant
+        let mut cpu = CPU::new("3,13,3,14,1,13,14,15,4,15,104,7,99,0,0,0");
+        cpu.set_taint_tracking(Dependence::Data);
+        assert_eq!(cpu.run(Some("2\n3")), Ok(ExitReason::Halt));
+        assert_eq!(
+            outputs(&cpu),
+            vec![
+                (5, sources(&[Source::Input(0), Source::Input(1)])),
+                (7, sources(&[])),
+            ]
+        );
+
+        let mut cpu = CPU::new(include_str!("../../input/2019/day2.txt"));
+        cpu.set_taint_tracking(Dependence::Data);
+        cpu.set_memory(1, 12);
+        cpu.set_memory(2, 2);
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(cpu.taint(0), sources(&[Source::Patch(1), Source::Patch(2)]));
+    }
+
+    #[test]
+    fn test_control() {
+        // A function at 14 outputs 1 if its input is not 0, and main outputs 5 once it returns
+        let program = "109,100,21101,9,0,0,1105,1,14,104,5,99,0,0,\
+                       3,50,1005,50,22,2105,1,0,104,1,2105,1,0";
+        let mut cpu = CPU::new(program);
+        cpu.set_memory_size(110);
+        cpu.set_taint_tracking(Dependence::Data);
+        assert_eq!(cpu.run(Some("1")), Ok(ExitReason::Halt));
+        assert_eq!(outputs(&cpu), vec![(1, sources(&[])), (5, sources(&[]))]);
+
+        let mut cpu = CPU::new(program);
+        cpu.set_memory_size(110);
+        cpu.set_taint_tracking(Dependence::Control);
+        assert_eq!(cpu.run(Some("1")), Ok(ExitReason::Halt));
+        assert_eq!(
+            outputs(&cpu),
+            vec![(1, sources(&[Source::Input(0)])), (5, sources(&[]))]
+        );
+    }
+
+    #[test]
+    fn test_amplifier_phase() {
+        // The phase picks a routine through a jump, so it only matters as control
+        let amplifier = include_str!("../../input/2019/day7.txt");
+        for (dependence, expected) in [
+            (Dependence::Data, sources(&[Source::Input(1)])),
+            (
+                Dependence::Control,
+                sources(&[Source::Input(0), Source::Input(1)]),
+            ),
+        ] {
+            let mut cpu = CPU::new(amplifier);
+            cpu.set_taint_tracking(dependence);
+            assert_eq!(cpu.run(Some("3\n7")), Ok(ExitReason::Halt));
+            assert_eq!(cpu.tainted_outputs()[0].sources, expected);
+        }
+    }
+
+    #[test]
+    fn test_joystick() {
+        // The score only changes when the paddle hits the ball, which the joystick decides
+        let mut cpu = CPU::new(include_str!("../../input/2019/day13.txt"));
+        cpu.set_memory_size(4096);
+        cpu.set_taint_tracking(Dependence::Control);
+        cpu.set_memory(0, 2);
+        while cpu.run(Some("0")) == Ok(ExitReason::InputRequired) {
+            let scored = cpu
+                .tainted_outputs()
+                .chunks(3)
+                .find(|tile| tile[0].value == -1 && tile[2].value > 0);
+            if let Some(tile) = scored {
+                assert!(tile[2].sources.contains(&Source::Input(0)));
+                return;
+            }
+        }
+        panic!("No score before the game ended");
+    }
+
+    #[test]
+    fn test_extension_store() {
+        // max(input, 7) into 11 by an extension, then output it
+        let mut registry = OpcodeRegistry::new();
+        registry
+            .register(
+                42,
+                "max",
+                &[OperandKind::Read, OperandKind::Read, OperandKind::Write],
+                |cpu, arguments| {
+                    cpu.store(arguments[2], arguments[0].max(arguments[1]))?;
+                    Ok(Flow::Next)
+                },
+            )
+            .unwrap();
+        let mut cpu = CPU::new("3,10,1042,10,7,11,4,11,99,0,0,0");
+        cpu.set_extensions(registry);
+        cpu.set_taint_tracking(Dependence::Data);
+        assert_eq!(cpu.run(Some("9")), Ok(ExitReason::Halt));
+        assert_eq!(outputs(&cpu), vec![(9, sources(&[Source::Input(0)]))]);
+    }
+}
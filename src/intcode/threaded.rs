@@ -0,0 +1,295 @@
+//! Runs every CPU on its own OS thread, reading input from a `Receiver` and sending each
+//! output through a `Sender`, so CPUs can be wired together with plain std channels.
+//!
+//! Once any CPU halts, faults or panics the runner shuts down: the remaining CPUs stop as
+//! soon as they are all waiting on empty inputs, since none of them can feed another any
+//! more. A CPU still busy computing is waited for, so values in flight between CPUs, like
+//! the last lap of the day 7 feedback loop, are not lost. A CPU whose input disconnects
+//! stops once it has read everything, whether or not the runner is shutting down.
+//!
+//! A CPU waiting for input sleeps on a condition variable that every send and every CPU
+//! stopping wakes, and looks at its input again then. Values sent from outside the runner
+//! are not tracked: send them before `run`, as they are otherwise only noticed when some
+//! CPU next sends or stops, and may be left unread by a shutdown.
+
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use super::{CpuResult, ExitReason, CPU};
+
+/// A CPU once its thread ended, and why it ended. CPUs that were stopped while waiting for
+/// input report `ExitReason::InputRequired`.
+#[derive(Debug)]
+pub struct Finished {
+    pub cpu: CPU,
+    pub exit: CpuResult<ExitReason>,
+}
+
+/// Index of a CPU whose thread panicked, which loses the CPU
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Panicked(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Running,
+    /// Waiting for input, and the input was empty when `Shared::sent` had this value
+    Idle(usize),
+    Done,
+}
+
+#[derive(Debug)]
+struct Shared {
+    stopping: bool,
+    /// Number of values sent by any CPU so far
+    sent: usize,
+    states: Vec<State>,
+}
+
+impl Shared {
+    /// Every CPU is done, or saw its input empty after the last value any CPU sent
+    fn quiet(&self) -> bool {
+        self.states
+            .iter()
+            .all(|state| *state == State::Done || *state == State::Idle(self.sent))
+    }
+}
+
+/// The shared state, and the condition variable that signals every change to it
+type Monitor = (Mutex<Shared>, Condvar);
+
+/// Marks a CPU done when its thread ends, by returning or by panicking
+struct Guard {
+    index: usize,
+    monitor: Arc<Monitor>,
+    /// Whether the way the CPU ended shuts the runner down
+    stopping: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let (shared, changed) = &*self.monitor;
+        // A poisoned lock only means another CPU panicked, the state is still consistent
+        let mut shared = shared
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        shared.states[self.index] = State::Done;
+        shared.stopping |= self.stopping;
+        changed.notify_all();
+    }
+}
+
+struct Node {
+    cpu: CPU,
+    input: Receiver<isize>,
+    output: Sender<isize>,
+}
+
+#[derive(Default)]
+pub struct ThreadedRunner {
+    nodes: Vec<Node>,
+}
+
+impl ThreadedRunner {
+    pub fn new() -> ThreadedRunner {
+        ThreadedRunner::default()
+    }
+
+    /// Add a CPU, returning its index in the results of `run`
+    pub fn add(&mut self, cpu: CPU, input: Receiver<isize>, output: Sender<isize>) -> usize {
+        self.nodes.push(Node { cpu, input, output });
+        self.nodes.len() - 1
+    }
+
+    /// Start every CPU and wait for all of them to finish, giving each CPU's result at its
+    /// index. A CPU whose thread panicked is only reported as `Panicked`.
+    pub fn run(self) -> Vec<Result<Finished, Panicked>> {
+        let monitor = Arc::new((
+            Mutex::new(Shared {
+                stopping: false,
+                sent: 0,
+                states: vec![State::Running; self.nodes.len()],
+            }),
+            Condvar::new(),
+        ));
+        let handles: Vec<_> = self
+            .nodes
+            .into_iter()
+            .enumerate()
+            .map(|(index, node)| {
+                let monitor = monitor.clone();
+                thread::spawn(move || {
+                    let mut guard = Guard {
+                        index,
+                        monitor: monitor.clone(),
+                        stopping: true,
+                    };
+                    // Declared after the guard so the channels close before it wakes anyone
+                    let Node {
+                        mut cpu,
+                        input,
+                        output,
+                    } = node;
+                    let exit = drive(&mut cpu, index, &input, &output, &monitor);
+                    guard.stopping = exit != Ok(ExitReason::InputRequired);
+                    Finished { cpu, exit }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .enumerate()
+            .map(|(index, handle)| handle.join().map_err(|_| Panicked(index)))
+            .collect()
+    }
+}
+
+fn drive(
+    cpu: &mut CPU,
+    index: usize,
+    input: &Receiver<isize>,
+    output: &Sender<isize>,
+    monitor: &Monitor,
+) -> CpuResult<ExitReason> {
+    cpu.set_exit_on_output();
+    let mut value: Option<String> = None;
+    loop {
+        // One value per run, so nothing is lost when the program outputs before reading
+        match cpu.run(value.take().as_deref())? {
+            ExitReason::OutputGenerated => {
+                if let Some(output_value) = cpu.get_last_output() {
+                    // Nobody listening is not the producer's problem, the value stays in
+                    // the CPU's output
+                    let _ = output.send(*output_value);
+                    let (shared, changed) = monitor;
+                    shared.lock().unwrap().sent += 1;
+                    changed.notify_all();
+                }
+            }
+            ExitReason::Halt => return Ok(ExitReason::Halt),
+            ExitReason::InputRequired => match recv(index, input, monitor) {
+                Some(received) => value = Some(received.to_string()),
+                None => return Ok(ExitReason::InputRequired),
+            },
+        }
+    }
+}
+
+/// Wait for the next input, None once the input disconnected or the runner shut down
+fn recv(index: usize, input: &Receiver<isize>, monitor: &Monitor) -> Option<isize> {
+    let (shared, changed) = monitor;
+    let mut shared = shared.lock().unwrap();
+    loop {
+        // Looking holding the lock means no CPU can send and count it in between
+        match input.try_recv() {
+            Ok(value) => {
+                shared.states[index] = State::Running;
+                return Some(value);
+            }
+            Err(TryRecvError::Disconnected) => return None,
+            Err(TryRecvError::Empty) => {
+                let idle = State::Idle(shared.sent);
+                if shared.states[index] != idle {
+                    // The others may have been waiting for this one to be quiet
+                    shared.states[index] = idle;
+                    changed.notify_all();
+                }
+                if shared.stopping && shared.quiet() {
+                    return None;
+                }
+                shared = changed.wait(shared).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::extension::OpcodeRegistry;
+    use super::super::CpuError;
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn finished(runner: ThreadedRunner) -> Vec<Finished> {
+        runner
+            .run()
+            .into_iter()
+            .map(|result| result.expect("No CPU panicked"))
+            .collect()
+    }
+
+    #[test]
+    fn test_pipeline() {
+        // Add one to every input, forever
+        let stage = || CPU::new("3,11,1001,11,1,11,4,11,1105,1,0,0");
+        let (input, first_in) = channel();
+        let (first_out, second_in) = channel();
+        let (second_out, results) = channel();
+        let mut runner = ThreadedRunner::new();
+        runner.add(stage(), first_in, first_out);
+        runner.add(stage(), second_in, second_out);
+        input.send(1).unwrap();
+        input.send(40).unwrap();
+        drop(input);
+
+        let finished = finished(runner);
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![3, 42]);
+        assert!(finished
+            .iter()
+            .all(|node| node.exit == Ok(ExitReason::InputRequired)));
+    }
+
+    #[test]
+    fn test_halt_stops_everyone() {
+        let (_keep_open, waiting_in) = channel();
+        let (waiting_out, _) = channel();
+        let (_, halting_in) = channel();
+        let (halting_out, outputs) = channel();
+        let mut runner = ThreadedRunner::new();
+        runner.add(CPU::new("3,0,99"), waiting_in, waiting_out);
+        runner.add(CPU::new("104,7,99"), halting_in, halting_out);
+
+        let finished = finished(runner);
+        assert_eq!(finished[0].exit, Ok(ExitReason::InputRequired));
+        assert_eq!(finished[1].exit, Ok(ExitReason::Halt));
+        assert_eq!(outputs.recv(), Ok(7));
+    }
+
+    #[test]
+    fn test_fault_stops_everyone() {
+        let (_keep_open, waiting_in) = channel();
+        let (waiting_out, _) = channel();
+        let (_, faulting_in) = channel();
+        let (faulting_out, _) = channel();
+        let mut runner = ThreadedRunner::new();
+        runner.add(CPU::new("3,0,99"), waiting_in, waiting_out);
+        let faulting = runner.add(CPU::new("42"), faulting_in, faulting_out);
+
+        let finished = finished(runner);
+        assert_eq!(finished[faulting].exit, Err(CpuError::InvalidOpcode(42, 0)));
+        assert_eq!(finished[0].exit, Ok(ExitReason::InputRequired));
+    }
+
+    #[test]
+    fn test_panic_stops_everyone() {
+        let mut registry = OpcodeRegistry::new();
+        registry
+            .register(42, "panic", &[], |_, _| panic!("Extension failed"))
+            .unwrap();
+        let mut panicking = CPU::new("42");
+        panicking.set_extensions(registry);
+
+        let (_keep_open, waiting_in) = channel();
+        let (waiting_out, _) = channel();
+        let (_, panicking_in) = channel();
+        let (panicking_out, _) = channel();
+        let mut runner = ThreadedRunner::new();
+        let waiting = runner.add(CPU::new("3,0,99"), waiting_in, waiting_out);
+        let index = runner.add(panicking, panicking_in, panicking_out);
+        let results = runner.run();
+        assert_eq!(results[index].as_ref().unwrap_err(), &Panicked(index));
+        // The CPU that did not panic is still there
+        let survivor = results[waiting].as_ref().expect("Only one CPU panicked");
+        assert_eq!(survivor.exit, Ok(ExitReason::InputRequired));
+    }
+}
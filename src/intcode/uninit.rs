@@ -0,0 +1,199 @@
+//! Detection of reads from memory nobody initialized. `set_memory_size` pads the program
+//! with zeros, so a program reading a cell that neither the program image nor any write
+//! ever set silently gets a 0. In strict mode every instruction about to run has its
+//! instruction words and its data operands checked against a map of initialized cells,
+//! which the program image fills and every write (including `set_memory`) extends.
+
+use crate::trace::{Event, Verbosity};
+
+use super::decode::ParameterMode;
+use super::extension::{Extension, OperandKind};
+use super::memory::Memory;
+use super::{CpuError, CpuResult, CPU};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UninitMode {
+    /// Emit an `Event::UninitializedRead` at info verbosity, once per cell
+    Warn,
+    /// Fail with `CpuError::UninitializedRead`
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct InitTracker {
+    mode: UninitMode,
+    initialized: Vec<bool>,
+}
+
+impl InitTracker {
+    pub(crate) fn new(mode: UninitMode, image_size: usize) -> InitTracker {
+        InitTracker {
+            mode,
+            initialized: vec![true; image_size],
+        }
+    }
+
+    pub(crate) fn write(&mut self, address: usize) {
+        if address >= self.initialized.len() {
+            self.initialized.resize(address + 1, false);
+        }
+        self.initialized[address] = true;
+    }
+
+    /// Forget cells past `size`, they are zeros again if the memory grows back
+    pub(crate) fn truncate(&mut self, size: usize) {
+        self.initialized.truncate(size);
+    }
+
+    fn is_initialized(&self, address: usize) -> bool {
+        self.initialized.get(address).copied().unwrap_or(false)
+    }
+
+    /// The first uninitialized cell the instruction at `ip` is about to read, if any
+    fn uninitialized_read(
+        &self,
+        memory: &Memory,
+        ip: usize,
+        relative_base: isize,
+        extension: Option<&Extension>,
+    ) -> Option<usize> {
+        let decoded = match extension {
+            Some(extension) => extension.decode(memory, ip),
+            None => memory.decode(ip),
+        };
+        let op = match decoded {
+            Some(op) => op,
+            None => return Some(ip).filter(|address| !self.is_initialized(*address)),
+        };
+        if let Some(address) = (ip..op.next()).find(|address| !self.is_initialized(*address)) {
+            return Some(address);
+        }
+        let write_operand = op.write_operand();
+        let reads = |index: usize| match extension {
+            Some(extension) => extension.operands[index] == OperandKind::Read,
+            None => write_operand != Some(index),
+        };
+        op.operands
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| reads(*index))
+            .filter_map(|(_, operand)| match operand.mode {
+                ParameterMode::Position => Some(operand.raw),
+                ParameterMode::Relative => Some(relative_base + operand.raw),
+                ParameterMode::Immediate => None,
+            })
+            .filter(|cell| *cell >= 0 && (*cell as usize) < memory.len())
+            .map(|cell| cell as usize)
+            .find(|address| !self.is_initialized(*address))
+    }
+}
+
+impl CPU {
+    /// Check every read against the cells the program image or a write initialized. Only
+    /// writes made after this call are known, so enable it before patching memory.
+    pub fn set_uninit_detection(&mut self, mode: UninitMode) {
+        let image_size = self.image_size.min(self.memory.len());
+        self.init_tracker = Some(InitTracker::new(mode, image_size));
+    }
+
+    pub fn clear_uninit_detection(&mut self) {
+        self.init_tracker = None;
+    }
+
+    /// Report the instruction at the instruction pointer if it reads uninitialized memory
+    pub(crate) fn check_uninitialized_reads(&mut self) -> CpuResult<()> {
+        let ip = self.instruction_pointer;
+        let pending = self.pending_extension();
+        let extension = pending
+            .as_ref()
+            .and_then(|(registry, opcode)| registry.get(*opcode));
+        let tracker = match self.init_tracker.as_mut() {
+            Some(tracker) => tracker,
+            None => return Ok(()),
+        };
+        let read = tracker.uninitialized_read(&self.memory, ip, self.relative_base, extension);
+        let address = match read {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        match tracker.mode {
+            UninitMode::Error => Err(CpuError::UninitializedRead(address, ip)),
+            UninitMode::Warn => {
+                self.tracer
+                    .emit(Verbosity::Info, || Event::UninitializedRead { ip, address });
+                // Once is enough, the same loop would otherwise warn on every lap
+                tracker.write(address);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ExitReason;
+    use super::*;
+    use crate::trace::MemoryTracer;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_read_past_image() {
+        // Output the cell at 10, which only exists after padding
+        let mut cpu = CPU::new("4,10,99");
+        cpu.set_memory_size(16);
+        cpu.set_uninit_detection(UninitMode::Error);
+        assert_eq!(cpu.run(None), Err(CpuError::UninitializedRead(10, 0)));
+
+        cpu.set_memory(10, 5);
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(cpu.get_output(), vec![5]);
+    }
+
+    #[test]
+    fn test_written_cells_are_initialized() {
+        // Store 7 at 20 and read it back through the relative base
+        let mut cpu = CPU::new("1101,3,4,20,109,15,204,5,99");
+        cpu.set_memory_size(32);
+        cpu.set_uninit_detection(UninitMode::Error);
+        assert_eq!(cpu.run(None), Ok(ExitReason::Halt));
+        assert_eq!(cpu.get_output(), vec![7]);
+    }
+
+    #[test]
+    fn test_extension_reads() {
+        use super::super::extension::{Flow, OpcodeRegistry};
+
+        // A host `out` that only reads its operand, here the cell at 10 past the image
+        let mut registry = OpcodeRegistry::new();
+        registry
+            .register(42, "out", &[OperandKind::Read], |cpu, arguments| {
+                cpu.output.push(arguments[0]);
+                Ok(Flow::Next)
+            })
+            .unwrap();
+        let mut cpu = CPU::new("42,10,99");
+        cpu.set_memory_size(16);
+        cpu.set_uninit_detection(UninitMode::Error);
+        cpu.set_extensions(registry);
+        assert_eq!(cpu.run(None), Err(CpuError::UninitializedRead(10, 0)));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let tracer = Arc::new(MemoryTracer::new(Verbosity::Info));
+        // Output the cell at 12 twice, then run off the end of the image
+        let mut cpu = CPU::new("4,12,4,12,1105,1,7");
+        cpu.set_memory_size(16);
+        cpu.set_tracer(tracer.clone());
+        cpu.set_uninit_detection(UninitMode::Warn);
+        assert!(matches!(cpu.run(None), Err(CpuError::InvalidOpcode(0, 7))));
+        assert_eq!(cpu.get_output(), vec![0, 0]);
+        assert_eq!(
+            tracer.events(),
+            vec![
+                Event::UninitializedRead { ip: 0, address: 12 },
+                Event::UninitializedRead { ip: 7, address: 7 },
+            ]
+        );
+    }
+}
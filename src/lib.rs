@@ -9,22 +9,18 @@ extern crate aoc_runner_derive;
 // #[macro_use]
 // extern crate lazy_static;
 
+/// Free form debug output, sent to the global tracer as a `Message` at `Debug` verbosity
 macro_rules! debug_print {
-    ($fmt:expr) => {
-        #[cfg(feature = "debugging")]
-        {
-            println!($fmt);
-        }
-    };
-    ($fmt:expr, $($arg:tt)*) => {
-        #[cfg(feature = "debugging")]
-        {
-            println!($fmt, $($arg)*);
-        }
+    ($($arg:tt)*) => {
+        $crate::trace::global().emit($crate::trace::Verbosity::Debug, || {
+            $crate::trace::Event::Message(format!($($arg)*))
+        })
     };
 }
 
-mod intcode;
+pub mod trace;
+
+pub mod intcode;
 
 pub mod day1;
 pub mod day2;
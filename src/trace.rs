@@ -0,0 +1,429 @@
+//! Runtime configurable tracing.
+//!
+//! Everything that used to be compiled in with the `debugging` feature now goes through a
+//! `Tracer`. The process wide tracer is configured with the `AOC_TRACE` environment variable:
+//!
+//! ```text
+//! AOC_TRACE=debug                    # debug events as text on stderr
+//! AOC_TRACE=trace:stderr             # every executed instruction as well
+//! AOC_TRACE=info:json:trace.jsonl    # JSON lines appended to trace.jsonl
+//! ```
+//!
+//! Anything that owns a tracer (`CPU`, `Robot`, `Arcade`, `Universe`) starts out with the
+//! global one. `CPU::set_tracer` swaps it for another; the puzzle types are private to their
+//! day, so their tests replace the `tracer` field directly.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Environment variable read by `global`
+pub const TRACE_ENV: &str = "AOC_TRACE";
+
+/// How much a tracer wants to hear about. Each level includes everything below it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    #[default]
+    Off,
+    /// Lifecycle events such as a CPU halting
+    Info,
+    /// I/O and peripheral events, plus free form debug messages
+    Debug,
+    /// Every executed instruction
+    Trace,
+}
+
+impl FromStr for Verbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" | "none" => Ok(Verbosity::Off),
+            "info" => Ok(Verbosity::Info),
+            "debug" => Ok(Verbosity::Debug),
+            "trace" => Ok(Verbosity::Trace),
+            other => Err(format!("Unknown verbosity `{}`", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    InstructionExecuted {
+        ip: usize,
+        text: String,
+    },
+    InputConsumed {
+        ip: usize,
+        address: usize,
+        value: isize,
+    },
+    InputRequired {
+        ip: usize,
+    },
+    OutputProduced {
+        ip: usize,
+        value: isize,
+    },
+    Halted {
+        ip: usize,
+    },
+    UninitializedRead {
+        ip: usize,
+        address: usize,
+    },
+    TileDrawn {
+        x: usize,
+        y: usize,
+        tile: String,
+    },
+    ScoreChanged {
+        score: isize,
+    },
+    PanelRead {
+        x: isize,
+        y: isize,
+        color: String,
+    },
+    PanelPainted {
+        x: isize,
+        y: isize,
+        color: String,
+    },
+    RobotMoved {
+        x: isize,
+        y: isize,
+        direction: String,
+    },
+    PlanetVisited {
+        id: usize,
+        name: String,
+    },
+    Message(String),
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::InstructionExecuted { .. } => "instruction_executed",
+            Event::InputConsumed { .. } => "input_consumed",
+            Event::InputRequired { .. } => "input_required",
+            Event::OutputProduced { .. } => "output_produced",
+            Event::Halted { .. } => "halted",
+            Event::UninitializedRead { .. } => "uninitialized_read",
+            Event::TileDrawn { .. } => "tile_drawn",
+            Event::ScoreChanged { .. } => "score_changed",
+            Event::PanelRead { .. } => "panel_read",
+            Event::PanelPainted { .. } => "panel_painted",
+            Event::RobotMoved { .. } => "robot_moved",
+            Event::PlanetVisited { .. } => "planet_visited",
+            Event::Message(_) => "message",
+        }
+    }
+
+    /// A single line JSON object with an `event` field holding `name`
+    pub fn to_json(&self) -> String {
+        let fields = match self {
+            Event::InstructionExecuted { ip, text } => {
+                format!(r#""ip":{},"text":{}"#, ip, json_string(text))
+            }
+            Event::InputConsumed { ip, address, value } => {
+                format!(r#""ip":{},"address":{},"value":{}"#, ip, address, value)
+            }
+            Event::InputRequired { ip } | Event::Halted { ip } => format!(r#""ip":{}"#, ip),
+            Event::OutputProduced { ip, value } => format!(r#""ip":{},"value":{}"#, ip, value),
+            Event::UninitializedRead { ip, address } => {
+                format!(r#""ip":{},"address":{}"#, ip, address)
+            }
+            Event::TileDrawn { x, y, tile } => {
+                format!(r#""x":{},"y":{},"tile":{}"#, x, y, json_string(tile))
+            }
+            Event::ScoreChanged { score } => format!(r#""score":{}"#, score),
+            Event::PanelRead { x, y, color } | Event::PanelPainted { x, y, color } => {
+                format!(r#""x":{},"y":{},"color":{}"#, x, y, json_string(color))
+            }
+            Event::RobotMoved { x, y, direction } => {
+                format!(
+                    r#""x":{},"y":{},"direction":{}"#,
+                    x,
+                    y,
+                    json_string(direction)
+                )
+            }
+            Event::PlanetVisited { id, name } => {
+                format!(r#""id":{},"name":{}"#, id, json_string(name))
+            }
+            Event::Message(text) => format!(r#""text":{}"#, json_string(text)),
+        };
+        format!(r#"{{"event":"{}",{}}}"#, self.name(), fields)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::InstructionExecuted { ip, text } => write!(f, "[{:>5}] {}", ip, text),
+            Event::InputConsumed { ip, address, value } => {
+                write!(f, "[{:>5}] Input {} -> @ {}", ip, value, address)
+            }
+            Event::InputRequired { ip } => write!(f, "[{:>5}] Input required", ip),
+            Event::OutputProduced { ip, value } => write!(f, "[{:>5}] Output {}", ip, value),
+            Event::Halted { ip } => write!(f, "[{:>5}] Halted", ip),
+            Event::UninitializedRead { ip, address } => {
+                write!(f, "[{:>5}] Read of uninitialized @ {}", ip, address)
+            }
+            Event::TileDrawn { x, y, tile } => write!(f, "Tile {} at ({}, {})", tile, x, y),
+            Event::ScoreChanged { score } => write!(f, "Score : {}", score),
+            Event::PanelRead { x, y, color } => write!(f, "Panel ({}, {}) is {}", x, y, color),
+            Event::PanelPainted { x, y, color } => {
+                write!(f, "Paint panel ({}, {}) {}", x, y, color)
+            }
+            Event::RobotMoved { x, y, direction } => {
+                write!(f, "Robot at ({}, {}) facing {}", x, y, direction)
+            }
+            Event::PlanetVisited { id, name } => write!(f, "Planet : {} - {}", id, name),
+            Event::Message(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+pub trait Tracer: fmt::Debug + Send + Sync {
+    fn verbosity(&self) -> Verbosity;
+
+    fn record(&self, event: &Event);
+
+    fn enabled(&self, verbosity: Verbosity) -> bool {
+        verbosity != Verbosity::Off && verbosity <= self.verbosity()
+    }
+}
+
+impl dyn Tracer {
+    /// Record the event built by `event` if this tracer wants events of `verbosity`. The
+    /// closure is only called when it does, so expensive formatting costs nothing when off.
+    pub fn emit<F: FnOnce() -> Event>(&self, verbosity: Verbosity, event: F) {
+        if self.enabled(verbosity) {
+            self.record(&event());
+        }
+    }
+}
+
+pub type SharedTracer = Arc<dyn Tracer>;
+
+/// Discards everything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullTracer;
+
+impl Tracer for NullTracer {
+    fn verbosity(&self) -> Verbosity {
+        Verbosity::Off
+    }
+
+    fn record(&self, _event: &Event) {}
+}
+
+/// Human readable lines on stderr
+#[derive(Debug, Clone, Copy)]
+pub struct StderrTracer {
+    verbosity: Verbosity,
+}
+
+impl StderrTracer {
+    pub fn new(verbosity: Verbosity) -> StderrTracer {
+        StderrTracer { verbosity }
+    }
+}
+
+impl Tracer for StderrTracer {
+    fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    fn record(&self, event: &Event) {
+        eprintln!("{}", event);
+    }
+}
+
+/// One JSON object per line, appended to a file
+#[derive(Debug)]
+pub struct JsonLinesTracer {
+    verbosity: Verbosity,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl JsonLinesTracer {
+    pub fn create<P: AsRef<Path>>(path: P, verbosity: Verbosity) -> io::Result<JsonLinesTracer> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLinesTracer {
+            verbosity,
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+}
+
+impl Tracer for JsonLinesTracer {
+    fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    fn record(&self, event: &Event) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writeln!(writer, "{}", event.to_json());
+            let _ = writer.flush();
+        }
+    }
+}
+
+/// Keeps every event in memory, mostly so tests can assert on them
+#[derive(Debug, Default)]
+pub struct MemoryTracer {
+    verbosity: Verbosity,
+    events: Mutex<Vec<Event>>,
+}
+
+impl MemoryTracer {
+    pub fn new(verbosity: Verbosity) -> MemoryTracer {
+        MemoryTracer {
+            verbosity,
+            events: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events
+            .lock()
+            .map(|events| events.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut events) = self.events.lock() {
+            events.clear();
+        }
+    }
+}
+
+impl Tracer for MemoryTracer {
+    fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    fn record(&self, event: &Event) {
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event.clone());
+        }
+    }
+}
+
+/// Build a tracer from a `<verbosity>[:stderr|:json:<path>]` spec, as used by `AOC_TRACE`
+pub fn from_spec(spec: &str) -> Result<SharedTracer, String> {
+    let mut parts = spec.trim().splitn(3, ':');
+    let verbosity = match parts.next() {
+        Some("") | None => Verbosity::Off,
+        Some(level) => level.parse()?,
+    };
+    if verbosity == Verbosity::Off {
+        return Ok(Arc::new(NullTracer));
+    }
+
+    match parts.next() {
+        None | Some("stderr") => Ok(Arc::new(StderrTracer::new(verbosity))),
+        Some("json") => {
+            let path = parts.next().ok_or("The json sink needs a path")?;
+            JsonLinesTracer::create(path, verbosity)
+                .map(|tracer| Arc::new(tracer) as SharedTracer)
+                .map_err(|error| format!("Unable to open {} : {}", path, error))
+        }
+        Some(sink) => Err(format!("Unknown trace sink `{}`", sink)),
+    }
+}
+
+/// The process wide tracer configured by `AOC_TRACE`, read once on first use
+pub fn global() -> SharedTracer {
+    static GLOBAL: OnceLock<SharedTracer> = OnceLock::new();
+    GLOBAL
+        .get_or_init(|| match std::env::var(TRACE_ENV) {
+            Ok(spec) => from_spec(&spec).unwrap_or_else(|error| {
+                eprintln!("Ignoring {} : {}", TRACE_ENV, error);
+                Arc::new(NullTracer)
+            }),
+            Err(_) => Arc::new(NullTracer),
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_filter() {
+        let tracer = MemoryTracer::new(Verbosity::Debug);
+        let shared: &dyn Tracer = &tracer;
+        shared.emit(Verbosity::Trace, || {
+            Event::Message("too detailed".to_string())
+        });
+        shared.emit(Verbosity::Debug, || Event::ScoreChanged { score: 3 });
+        shared.emit(Verbosity::Info, || Event::Halted { ip: 7 });
+        assert_eq!(
+            tracer.events(),
+            vec![Event::ScoreChanged { score: 3 }, Event::Halted { ip: 7 }]
+        );
+    }
+
+    #[test]
+    fn test_null_tracer_skips_closure() {
+        let tracer: &dyn Tracer = &NullTracer;
+        tracer.emit(Verbosity::Info, || panic!("Closure should not run"));
+    }
+
+    #[test]
+    fn test_json() {
+        let event = Event::TileDrawn {
+            x: 1,
+            y: 2,
+            tile: "Ball".to_string(),
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"tile_drawn","x":1,"y":2,"tile":"Ball"}"#
+        );
+        assert_eq!(
+            Event::Message("say \"hi\"\n".to_string()).to_json(),
+            r#"{"event":"message","text":"say \"hi\"\n"}"#
+        );
+    }
+
+    #[test]
+    fn test_from_spec() {
+        assert_eq!(from_spec("").unwrap().verbosity(), Verbosity::Off);
+        assert_eq!(from_spec("debug").unwrap().verbosity(), Verbosity::Debug);
+        assert_eq!(
+            from_spec("trace:stderr").unwrap().verbosity(),
+            Verbosity::Trace
+        );
+        assert!(from_spec("loud").is_err());
+        assert!(from_spec("info:json").is_err());
+        assert!(from_spec("info:syslog").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Runs the intcode conformance corpus in `tests/conformance` against every engine.
+//!
+//! Each file holds cases of the form
+//!
+//! ```text
+//! [name]
+//! program = 3,0,4,0,99
+//! input = 37
+//! output = 37
+//! memory = 37,0,4,0,99
+//! memory_size = 1024
+//! ```
+//!
+//! Only `program` is required. `memory` is compared against the start of the final memory,
+//! so it can stop after the last interesting cell.
+
+use std::fs;
+use std::path::Path;
+
+use advent_of_code_2019::intcode::{ExitReason, CPU};
+
+const STEP_LIMIT: usize = 100_000;
+
+#[derive(Debug, Default)]
+struct Case {
+    name: String,
+    program: Vec<isize>,
+    input: Vec<isize>,
+    output: Vec<isize>,
+    memory: Option<Vec<isize>>,
+    memory_size: Option<usize>,
+}
+
+/// What an engine leaves behind after running a case to completion
+#[derive(Debug)]
+struct Outcome {
+    output: Vec<isize>,
+    /// The first `case.memory.len()` cells of the final memory
+    memory: Vec<isize>,
+}
+
+trait Engine {
+    fn name(&self) -> &'static str;
+    fn run(&self, case: &Case) -> Result<Outcome, String>;
+}
+
+struct Interpreter;
+
+impl Engine for Interpreter {
+    fn name(&self) -> &'static str {
+        "intcode::CPU"
+    }
+
+    fn run(&self, case: &Case) -> Result<Outcome, String> {
+        let mut cpu = CPU::from_memory(case.program.clone());
+        if let Some(size) = case.memory_size {
+            cpu.set_memory_size(size);
+        }
+        cpu.set_step_limit(STEP_LIMIT);
+        let input: Vec<String> = case.input.iter().map(|value| value.to_string()).collect();
+        match cpu.run(Some(&input.join("\n"))) {
+            Ok(ExitReason::Halt) => {}
+            other => return Err(format!("Expected a halt, got {:?}", other)),
+        }
+
+        let cells = case.memory.as_ref().map_or(0, |memory| memory.len());
+        Ok(Outcome {
+            output: cpu.get_output(),
+            memory: (0..cells).map(|address| cpu.get_memory(address)).collect(),
+        })
+    }
+}
+
+fn engines() -> Vec<Box<dyn Engine>> {
+    vec![Box::new(Interpreter)]
+}
+
+fn parse_values(value: &str) -> Vec<isize> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse().expect("Invalid value in case file"))
+        .collect()
+}
+
+fn parse_cases(file: &str, source: &str) -> Vec<Case> {
+    let mut cases: Vec<Case> = vec![];
+    for line in source.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            cases.push(Case {
+                name: format!("{}/{}", file, &line[1..line.len() - 1]),
+                ..Case::default()
+            });
+            continue;
+        }
+
+        let case = cases
+            .last_mut()
+            .unwrap_or_else(|| panic!("{} : `{}` is outside of a case", file, line));
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = parts
+            .next()
+            .unwrap_or_else(|| panic!("{} : expected KEY = VALUE, got `{}`", file, line));
+        match key {
+            "program" => case.program = parse_values(value),
+            "input" => case.input = parse_values(value),
+            "output" => case.output = parse_values(value),
+            "memory" => case.memory = Some(parse_values(value)),
+            "memory_size" => case.memory_size = Some(value.trim().parse().unwrap()),
+            _ => panic!("{} : unknown key `{}`", file, key),
+        }
+    }
+    cases
+}
+
+fn load_corpus() -> Vec<Case> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let mut files: Vec<_> = fs::read_dir(&directory)
+        .expect("Missing conformance directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .collect();
+    files.sort();
+
+    files
+        .iter()
+        .flat_map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            parse_cases(&name, &fs::read_to_string(path).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn conformance() {
+    let cases = load_corpus();
+    assert!(!cases.is_empty(), "The conformance corpus is empty");
+
+    let mut failures = vec![];
+    for engine in engines() {
+        for case in &cases {
+            assert!(!case.program.is_empty(), "{} has no program", case.name);
+            let outcome = match engine.run(case) {
+                Ok(outcome) => outcome,
+                Err(error) => {
+                    failures.push(format!("{} on {} : {}", case.name, engine.name(), error));
+                    continue;
+                }
+            };
+            if outcome.output != case.output {
+                failures.push(format!(
+                    "{} on {} : output {:?}, expected {:?}",
+                    case.name,
+                    engine.name(),
+                    outcome.output,
+                    case.output
+                ));
+            }
+            if let Some(memory) = &case.memory {
+                if &outcome.memory != memory {
+                    failures.push(format!(
+                        "{} on {} : memory {:?}, expected {:?}",
+                        case.name,
+                        engine.name(),
+                        outcome.memory,
+                        memory
+                    ));
+                }
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,196 @@
+//! Talks to the GDB stub over a local socket the way gdb would, one scripted packet at a
+//! time.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use advent_of_code_2019::intcode::gdb::GdbStub;
+use advent_of_code_2019::intcode::CPU;
+
+/// Read the input into 9, add 1 into 10 and output it
+const INCREMENT: &str = "3,9,1001,9,1,10,4,10,99,0,0";
+
+struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    acknowledge: bool,
+    /// Everything the stub printed on the console
+    console: String,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn hex(text: &str) -> String {
+    text.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> String {
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap() as char)
+        .collect()
+}
+
+impl Client {
+    fn send(&mut self, packet: &str) {
+        write!(self.writer, "${}#{:02x}", packet, checksum(packet)).unwrap();
+        if self.acknowledge {
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+', "Stub rejected `{}`", packet);
+        }
+    }
+
+    fn receive(&mut self) -> String {
+        let mut start = [0];
+        self.reader.read_exact(&mut start).unwrap();
+        assert_eq!(start[0], b'$');
+        let mut data = vec![];
+        self.reader.read_until(b'#', &mut data).unwrap();
+        data.pop();
+        let data = String::from_utf8(data).unwrap();
+        let mut sum = [0; 2];
+        self.reader.read_exact(&mut sum).unwrap();
+        let sum = u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap();
+        assert_eq!(sum, checksum(&data), "Bad checksum on `{}`", data);
+        if self.acknowledge {
+            self.writer.write_all(b"+").unwrap();
+        }
+        data
+    }
+
+    /// Send `packet` and return the reply, collecting console output on the way
+    fn request(&mut self, packet: &str) -> String {
+        self.send(packet);
+        loop {
+            let reply = self.receive();
+            match reply.strip_prefix('O') {
+                Some(text) if reply != "OK" => self.console.push_str(&unhex(text)),
+                _ => return reply,
+            }
+        }
+    }
+
+    fn monitor(&mut self, command: &str) -> String {
+        self.request(&format!("qRcmd,{}", hex(command)))
+    }
+}
+
+/// A stub serving `program` on a free port, and a client connected to it
+fn attach(program: &str) -> (Client, JoinHandle<GdbStub>) {
+    attach_cpu(CPU::new(program))
+}
+
+fn attach_cpu(cpu: CPU) -> (Client, JoinHandle<GdbStub>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub::new(cpu);
+        stub.serve(stream).unwrap();
+        stub
+    });
+
+    let stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut client = Client {
+        reader: BufReader::new(stream.try_clone().unwrap()),
+        writer: stream,
+        acknowledge: true,
+        console: String::new(),
+    };
+    let supported = client.request("qSupported:multiprocess+;xmlRegisters=i386");
+    assert!(supported.contains("qXfer:features:read+"), "{}", supported);
+    assert_eq!(client.request("QStartNoAckMode"), "OK");
+    client.acknowledge = false;
+    (client, server)
+}
+
+#[test]
+fn test_session() {
+    let (mut client, server) = attach(INCREMENT);
+    assert_eq!(client.request("?"), "S05");
+    assert_eq!(client.request("g"), "0".repeat(32));
+    assert_eq!(client.request("m0,8"), "0300000000000000");
+    assert!(client
+        .request("qXfer:features:read:target.xml:0,1000")
+        .contains(r#"<reg name="rb""#));
+
+    // Nothing to read yet
+    assert_eq!(client.request("c"), "S05");
+    assert!(client.console.contains("monitor input"));
+    assert_eq!(client.request("p0"), "0000000000000000");
+
+    // Stop before the output, at cell 6
+    assert_eq!(client.monitor("input 41"), "OK");
+    assert_eq!(client.request("Z0,30,1"), "OK");
+    assert_eq!(client.request("c"), "S05");
+    assert_eq!(client.request("p0"), "0600000000000000");
+    assert_eq!(client.request("m50,8"), "2a00000000000000");
+
+    // Change the value about to be output
+    assert_eq!(client.request("M50,8:6400000000000000"), "OK");
+    client.console.clear();
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.console, "100\n");
+    assert_eq!(client.request("p0"), "0800000000000000");
+
+    assert_eq!(client.request("P1=0500000000000000"), "OK");
+    assert_eq!(client.request("g"), "08000000000000000500000000000000");
+    assert_eq!(client.request("c"), "W00");
+    assert_eq!(client.request("D"), "OK");
+
+    let cpu = server.join().unwrap().into_cpu();
+    assert_eq!(cpu.get_output(), vec![100]);
+    assert_eq!(cpu.get_memory(9), 41);
+}
+
+#[test]
+fn test_fault() {
+    // Jump to the invalid opcode at 7
+    let (mut client, server) = attach("1105,1,7,99,0,0,0,98");
+    assert_eq!(client.request("c"), "S04");
+    assert!(client.console.contains("InvalidOpcode(98, 7)"));
+    assert!(client.console.contains("#0 7 in main"));
+    assert_eq!(client.request("p0"), "0700000000000000");
+    assert_eq!(client.request("m400,8"), "E14");
+    assert_eq!(client.request("mffffffffffffffff,8"), "E14");
+    assert_eq!(client.request("Mfffffffffffffffe,2:0000"), "E14");
+    assert_eq!(client.monitor("bogus"), "E01");
+    assert_eq!(client.request("D"), "OK");
+    server.join().unwrap();
+}
+
+#[test]
+fn test_interrupt() {
+    // Loop forever
+    let (mut client, server) = attach("1105,1,0");
+    client.send("c");
+    thread::sleep(Duration::from_millis(20));
+    client.writer.write_all(&[0x03]).unwrap();
+    assert_eq!(client.receive(), "S02");
+    assert_eq!(client.request("p0"), "0000000000000000");
+    // A hang up ends the session too
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_step_limit() {
+    // Loop forever, but only for as long as the limit allows
+    let mut cpu = CPU::new("1105,1,0");
+    cpu.set_step_limit(10);
+    let (mut client, server) = attach_cpu(cpu);
+    assert_eq!(client.request("c"), "S06");
+    assert!(client.console.contains("StepLimitReached(0)"));
+    assert_eq!(client.request("s"), "S06");
+    assert_eq!(client.request("D"), "OK");
+    assert_eq!(server.join().unwrap().cpu().get_steps(), 10);
+}